@@ -1,25 +1,7 @@
-/// A file system containing a single file of a large size.
-/// The file is filled with a repeating pattern of bytes, from 0 to 255.
+//! A file system containing a single file of a large size.
+//! The file is filled with a repeating pattern of bytes, from 0 to 255.
 
-use fusible::{RoutableFilesystem, handler::{DirectoryListing, FileHandler}};
-
-#[derive(Debug, Clone)]
-struct ByteCycle {
-    size: u64,
-}
-
-impl ByteCycle {
-    pub fn new(size: u64) -> ByteCycle {
-        ByteCycle { size }
-    }
-}
-
-impl FileHandler for ByteCycle {
-
-    fn get_size(&self) -> u64 {
-        self.size
-    }
-}
+use fusible::{MountOptions, RoutableFilesystem, handler::DirectoryListing};
 
 fn main() {
     env_logger::init();
@@ -28,9 +10,13 @@ fn main() {
     let mut fs = RoutableFilesystem::new();
 
     let root = DirectoryListing::new()
-        .add_file("cycle", ByteCycle::new(1024 * 1024 * 1024));
+        .add_fn_file("cycle", || 1024 * 1024 * 1024, |offset, size| {
+            Ok((offset..offset + size as u64).map(|i| (i % 256) as u8).collect())
+        });
     
     fs.set_root(root);
 
-    fs.mount(&mountpoint);
-}
\ No newline at end of file
+    // Unmount cleanly on Ctrl-C instead of leaving a stale mountpoint behind
+    let shutdown = fs.mount_until_signal(&mountpoint, &MountOptions::new()).unwrap();
+    std::process::exit(shutdown.exit_code());
+}
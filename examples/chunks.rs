@@ -0,0 +1,60 @@
+//! A file system that shows a large file as a directory of 1MB pieces,
+//! named `0.part`, `1.part` and so on, like the `split-file-into-chunks` binary.
+//! The whole layout is a single route.
+
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use fusible::{MountOptions, RoutableFilesystem, handler::{File, FileHandler}, router::{Params, Router}};
+
+const PART_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+struct Piece {
+    file: Arc<std::fs::File>,
+    start: u64,
+    size: u64,
+}
+
+impl FileHandler for Piece {
+    fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+        let mut buf = vec![0; size as usize];
+        self.file.read_exact_at(&mut buf, self.start + offset).map_err(|_| libc::EIO)?;
+        Ok(buf)
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let path = std::env::args().nth(1).expect("missing file argument");
+    let mountpoint = std::env::args().nth(2).expect("missing mountpoint argument");
+    let file = Arc::new(std::fs::File::open(path).expect("failed to open file"));
+    let file_size = file.metadata().expect("failed to stat file").len();
+    let pieces = file_size.div_ceil(PART_SIZE);
+
+    let router = Router::new().route(
+        "/{n}.part",
+        move || (0..pieces).map(|n| Params::new().with("n", n)).collect(),
+        move |params| {
+            let n: u64 = params.parse("n")?;
+            // Pieces past the end of the file don't exist
+            if n >= pieces {
+                return None;
+            }
+            let start = n * PART_SIZE;
+            let size = PART_SIZE.min(file_size - start);
+            Some(File::from_impl(Piece { file: file.clone(), start, size }).into())
+        },
+    );
+
+    let mut fs = RoutableFilesystem::new();
+    fs.set_root(router);
+
+    let shutdown = fs.mount_until_signal(&mountpoint, &MountOptions::new()).unwrap();
+    std::process::exit(shutdown.exit_code());
+}
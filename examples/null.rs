@@ -1,4 +1,4 @@
-/// A null filesystem. All operations return ENOSYS.
+//! A null filesystem. All operations return ENOSYS.
 
 use fusible::RoutableFilesystem;
 
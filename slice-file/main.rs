@@ -1,6 +1,7 @@
 use std::env;
 use std::ffi::OsStr;
 use std::fs::File;
+use std::path::Path;
 use std::io::{Seek, Read};
 use std::os::unix::prelude::FileExt;
 
@@ -8,6 +9,8 @@ use libc::ENOENT;
 use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
 use time::Timespec;
 
+use fusible::statfs::StatFs;
+
 
 struct HelloFS {
     file: std::fs::File,
@@ -93,7 +96,7 @@ impl Filesystem for HelloFS {
             let true_end_byte = expected_end_byte.min(self.end_byte);
             let true_size = true_end_byte - true_offset;
             // Seek to offset
-            file.seek(std::io::SeekFrom::Start(true_offset as u64)).unwrap();
+            file.seek(std::io::SeekFrom::Start(true_offset)).unwrap();
             // Read size bytes
             let mut buf = vec![0; true_size as usize];
             file.read_exact(&mut buf).unwrap();
@@ -156,6 +159,13 @@ impl Filesystem for HelloFS {
         reply.opened(0, flags);
     }
 
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuse::ReplyStatfs) {
+        // Writes land on the slice in place, so the whole slice is the space there is to write to.
+        // No files can be created, but tools that check for a free inode before writing would refuse with none
+        let stats = StatFs::with_capacity(self.size(), self.size(), 2, 1);
+        reply.statfs(stats.blocks, stats.bfree, stats.bavail, stats.files, stats.ffree, stats.bsize, stats.namelen, stats.frsize);
+    }
+
     fn setattr(&mut self, req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
         // The user cannot change attributes here
         // Just respond with the old attributes for the file.
@@ -179,7 +189,8 @@ fn main() {
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-    fuse::mount(HelloFS{
+    let shutdown = fusible::signal::mount_until_signal(HelloFS{
         file, begin_byte, end_byte,
-    }, &mountpoint, &options).unwrap();
+    }, Path::new(&mountpoint), &options).unwrap();
+    std::process::exit(shutdown.exit_code());
 }
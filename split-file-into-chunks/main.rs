@@ -1,6 +1,7 @@
 use std::env;
 use std::ffi::OsStr;
 use std::fs::File;
+use std::path::Path;
 use std::io::{Seek, Read, Write};
 use libc::ENOENT;
 use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
@@ -34,19 +35,18 @@ impl HelloFS {
             println!("Requested part is strictly after the end of the file ({name_number}*{PART_SIZE} = {} >= {file_size})", name_number*PART_SIZE);
             return None;
         }
-        let size;
         // If requested part is the last one, return its size as the size of the file
-        if (name_number+1)*PART_SIZE >= file_size {
-            size = file_size - name_number*PART_SIZE;
+        let size = if (name_number+1)*PART_SIZE >= file_size {
+            file_size - name_number*PART_SIZE
         } else {
-            size = PART_SIZE;
-        }
+            PART_SIZE
+        };
 
 
         let one_second = Timespec::new(1, 0);
         Some(FileAttr {
             ino: name_number + 2,
-            size: size,
+            size,
             blocks: size/512,
             atime: one_second,                                  // 1970-01-01 00:00:00
             mtime: one_second,
@@ -73,10 +73,8 @@ impl Filesystem for HelloFS {
             if let Some(attr) = self.lookup_piece_info(text_name) {
                 let one_second = Timespec::new(1, 0);
                 reply.entry(&one_second, &attr, 0);
-                return;
             } else {
                 reply.error(ENOENT);
-                return;
             }
         } else {
             reply.error(ENOENT);
@@ -218,7 +216,8 @@ fn main() {
     
     let handle_lock = std::sync::Mutex::new(());
 
-    fuse::mount(HelloFS{
+    let shutdown = fusible::signal::mount_until_signal(HelloFS{
         file, file_size, handle_lock
-    }, &mountpoint, &options).unwrap();
+    }, Path::new(&mountpoint), &options).unwrap();
+    std::process::exit(shutdown.exit_code());
 }
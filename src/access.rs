@@ -0,0 +1,250 @@
+//! Who is asking, and whether they may.
+//!
+//! Every request carries the uid, gid and pid of the process that made it.
+//! Handlers get it as a [`RequestContext`] through the `*_as` methods
+//! (like [`FileHandler::read_as`](crate::handler::FileHandler::read_as)),
+//! and an [`AccessPolicy`] set with [`RoutableFilesystem::set_access_policy`](crate::RoutableFilesystem::set_access_policy)
+//! can deny requests by path before they reach any handler.
+//!
+//! Alternatively, mounting with [`MountOptions::default_permissions`](crate::MountOptions::default_permissions)
+//! has the kernel check the modes and owners the handlers report, like on a regular filesystem.
+
+use libc::{R_OK, W_OK, X_OK};
+
+/// The process that made a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RequestContext {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl RequestContext {
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Self {
+        RequestContext { uid, gid, pid }
+    }
+
+    /// The context of the current process, e.g. for calling handlers directly.
+    pub fn current_process() -> Self {
+        unsafe { RequestContext::new(libc::getuid(), libc::getgid(), std::process::id()) }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+impl From<&fuse::Request<'_>> for RequestContext {
+    fn from(req: &fuse::Request<'_>) -> Self {
+        RequestContext::new(req.uid(), req.gid(), req.pid())
+    }
+}
+
+/// What a request is trying to do, for an [`AccessPolicy`] to decide on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Find an item by name, which is needed for everything else.
+    /// Items that can't be looked up are also left out of directory listings.
+    Lookup,
+    /// Read a file or symlink, list a directory, or read extended attributes.
+    Read,
+    /// Write to or truncate a file, or change its extended attributes.
+    Write,
+}
+
+/// Decides whether a request may go ahead; denied requests fail with `EACCES`.
+///
+/// `path` is the item's path from the root of the filesystem, like `/admin/secrets`.
+///
+/// ```
+/// # use fusible::{RoutableFilesystem, access::Operation};
+/// let mut fs = RoutableFilesystem::new();
+/// fs.set_access_policy(|context, path, operation| {
+///     context.is_root() || !path.starts_with("/admin") || operation == Operation::Lookup
+/// });
+/// ```
+pub type AccessPolicy<'a> = Box<dyn Fn(&RequestContext, &str, Operation) -> bool + Send + Sync + 'a>;
+
+/// Check an `access(2)` mask against the permission bits and owner of an item, like the kernel would.
+///
+/// Root may do anything, except execute things that nobody can execute.
+pub(crate) fn mode_allows(attr: &fuse::FileAttr, context: &RequestContext, mask: i32) -> bool {
+    let perm = attr.perm as i32;
+    if context.is_root() {
+        return mask & X_OK == 0 || perm & 0o111 != 0 || attr.kind == fuse::FileType::Directory;
+    }
+    let granted = if context.uid == attr.uid {
+        perm >> 6
+    } else if context.gid == attr.gid {
+        perm >> 3
+    } else {
+        perm
+    } & 0o7;
+    mask & (R_OK | W_OK | X_OK) & !granted == 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use libc::{c_int, EACCES, ENOTSUP, F_OK, O_RDONLY, O_WRONLY};
+
+    use super::*;
+    use crate::handler::{DirectoryHandler, DirectoryListing, FileHandler, PathHandler, StaticFile, File, Symlink};
+    use crate::metadata::Metadata;
+    use crate::testing::Harness;
+
+    const ALICE: RequestContext = RequestContext { uid: 1000, gid: 1000, pid: 10 };
+    const BOB: RequestContext = RequestContext { uid: 1001, gid: 1001, pid: 11 };
+
+    /// A file that greets whoever reads it.
+    #[derive(Debug)]
+    struct Whoami;
+
+    impl FileHandler for Whoami {
+        fn get_size(&self) -> u64 {
+            4
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(b"????".to_vec())
+        }
+
+        fn read_as(&self, context: &RequestContext, _offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(format!("{:04}", context.uid % 10000).into_bytes()[..size as usize].to_vec())
+        }
+    }
+
+    /// A directory with one entry per user, visible only to that user.
+    #[derive(Debug)]
+    struct Homes;
+
+    impl<'a> DirectoryHandler<'a> for Homes {
+        fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+            Vec::new()
+        }
+
+        fn list_as(&self, context: &RequestContext) -> Vec<(String, PathHandler<'a>)> {
+            vec![(context.uid.to_string(), DirectoryListing::new().into())]
+        }
+
+        fn lookup_as(&self, context: &RequestContext, name: &str) -> Option<PathHandler<'a>> {
+            self.list_as(context).into_iter().find(|(n, _)| n == name).map(|(_, handler)| handler)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Sink(Mutex<Vec<u8>>);
+
+    impl FileHandler for Sink {
+        fn get_size(&self) -> u64 {
+            0
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(Vec::new())
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+
+        fn write(&self, _offset: u64, data: &[u8]) -> Result<u32, c_int> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len() as u32)
+        }
+    }
+
+    fn harness() -> Harness<'static> {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_file("whoami", Whoami)
+            .add_dynamic_dir("home", Homes)
+            .add_dir("admin", DirectoryListing::new()
+                .add_static_file("secrets", "hunter2")
+                .add("vault", Symlink::new("/private"))
+                .add("notes", File::from_impl(Sink(Mutex::new(Vec::new()))).with_xattr("user.tag", "secret")))
+            .add("private", File::from_impl(StaticFile::from("alice only")).with_metadata(Metadata::new().perm(0o600).owner(1000, 1000)))
+            .add_file("sink", Sink(Mutex::new(Vec::new()))));
+        harness.filesystem().set_access_policy(|context, path, operation| {
+            match path {
+                _ if path.starts_with("/admin/") => context.is_root(),
+                "/sink" => operation != Operation::Write || context.pid != BOB.pid,
+                _ => true,
+            }
+        });
+        harness
+    }
+
+    #[test]
+    fn handlers_see_who_is_asking() {
+        let mut harness = harness();
+        harness.set_context(ALICE);
+        assert_eq!(harness.read_to_end("/whoami"), Ok(b"1000".to_vec()));
+        assert!(harness.exists("/home/1000"));
+        harness.set_context(BOB);
+        assert_eq!(harness.read_to_end("/whoami"), Ok(b"1001".to_vec()));
+        assert!(!harness.exists("/home/1000"));
+    }
+
+    #[test]
+    fn the_policy_can_deny_requests() {
+        let mut harness = harness();
+        harness.set_context(ALICE);
+        assert_eq!(harness.resolve("/admin/secrets"), Err(EACCES));
+        assert!(harness.readdir("/admin").unwrap().is_empty());
+        assert_eq!(harness.write("/sink", 0, b"hi"), Ok(2));
+
+        harness.set_context(BOB);
+        assert_eq!(harness.write("/sink", 0, b"hi"), Err(EACCES));
+        assert!(harness.open("/sink", O_RDONLY).is_ok());
+
+        harness.set_context(RequestContext::default());
+        assert_eq!(harness.read_to_end("/admin/secrets"), Ok(b"hunter2".to_vec()));
+    }
+
+    #[test]
+    fn access_checks_modes_and_the_policy() {
+        let mut harness = harness();
+        harness.set_context(ALICE);
+        assert_eq!(harness.access("/private", R_OK), Ok(()));
+        // Static files never take writes, whatever their mode says
+        assert_eq!(harness.access("/private", W_OK), Err(EACCES));
+        assert_eq!(harness.access("/sink", W_OK), Err(EACCES));
+
+        harness.set_context(BOB);
+        assert_eq!(harness.access("/private", F_OK), Ok(()));
+        assert_eq!(harness.access("/private", R_OK), Err(EACCES));
+        assert_eq!(harness.open("/whoami", O_WRONLY), Err(EACCES));
+
+        // Root gets past the modes, but not the policy
+        harness.set_context(RequestContext { pid: BOB.pid, ..RequestContext::default() });
+        assert_eq!(harness.access("/private", R_OK), Ok(()));
+        assert_eq!(harness.access("/sink", W_OK), Err(EACCES));
+        harness.set_context(RequestContext::default());
+        assert_eq!(harness.access("/sink", W_OK), Ok(()));
+    }
+
+    #[test]
+    fn the_policy_covers_links_and_xattrs() {
+        let mut harness = harness();
+        let vault = harness.resolve("/admin/vault").unwrap();
+        let notes = harness.resolve("/admin/notes").unwrap();
+        assert_eq!(harness.readlink("/admin/vault"), Ok("/private".to_string()));
+        assert_eq!(harness.getxattr("/admin/notes", "user.tag"), Ok(b"secret".to_vec()));
+
+        // Inodes the kernel already knows about are still checked
+        harness.set_context(ALICE);
+        let fs = harness.filesystem();
+        assert_eq!(fs.do_readlink(vault), Err(EACCES));
+        assert_eq!(fs.do_getxattr(notes, "user.tag"), Err(EACCES));
+        assert_eq!(fs.do_listxattr(notes), Err(EACCES));
+        assert_eq!(fs.do_setxattr(notes, "user.tag", b"public"), Err(EACCES));
+        assert_eq!(fs.do_removexattr(notes, "user.tag"), Err(EACCES));
+
+        // Root gets through to the handler, which doesn't keep xattrs of its own
+        harness.set_context(RequestContext::default());
+        assert_eq!(harness.listxattr("/admin/notes"), Ok(vec!["user.tag".to_string()]));
+        assert_eq!(harness.setxattr("/admin/notes", "user.tag", b"public"), Err(ENOTSUP));
+    }
+}
@@ -0,0 +1,260 @@
+//! File handlers whose data comes from async code.
+//!
+//! The FUSE session loop is single-threaded, so a slow read would hold up every other request.
+//! Reads and writes on an [`AsyncFileHandler`] are instead handed to a pool of worker threads,
+//! which poll the returned future and reply to the kernel once it completes.
+//!
+//! The futures are polled by fusible's own threads, not by an async runtime,
+//! so they must not rely on a runtime being current (like tokio's I/O types do).
+//! To use such code, spawn the work on your runtime and await a channel that it completes.
+
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::Thread;
+
+use libc::{c_int, EROFS};
+
+use crate::handler::FileHandler;
+
+/// A boxed future that can be sent to a worker thread.
+pub type BoxFuture<'f, T> = Pin<Box<dyn Future<Output = T> + Send + 'f>>;
+
+/// The async counterpart of [`FileHandler`].
+///
+/// Wrap one with [`File::from_async`](crate::handler::File::from_async) to add it to a tree.
+pub trait AsyncFileHandler: std::fmt::Debug + Send + Sync {
+    /// The size of the file.
+    ///
+    /// This is asked for on every `getattr`, so it is synchronous and should be cheap.
+    fn get_size(&self) -> u64;
+
+    /// Read `size` bytes starting at `offset`.
+    ///
+    /// As with [`FileHandler::read`], the request is already clamped to the end of the file.
+    fn read(&self, offset: u64, size: u32) -> BoxFuture<'_, Result<Vec<u8>, c_int>>;
+
+    /// Whether this file accepts writes.
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Write `data` starting at `offset`, returning the number of bytes written.
+    fn write(&self, _offset: u64, _data: Vec<u8>) -> BoxFuture<'_, Result<u32, c_int>> {
+        Box::pin(async { Err(EROFS) })
+    }
+
+    /// Change the size of the file, as requested by `truncate(2)` or `open(2)` with `O_TRUNC`.
+    fn truncate(&self, _size: u64) -> BoxFuture<'_, Result<(), c_int>> {
+        Box::pin(async { Err(EROFS) })
+    }
+}
+
+/// Lets an [`AsyncFileHandler`] be used wherever a [`FileHandler`] is expected,
+/// by blocking on its futures.
+///
+/// [`RoutableFilesystem`](crate::RoutableFilesystem) does not go through this for reads and writes;
+/// it is used by [`testing::Harness`](crate::testing::Harness) and for everything else.
+#[derive(Debug)]
+pub(crate) struct Blocking(pub(crate) Arc<dyn AsyncFileHandler>);
+
+impl FileHandler for Blocking {
+    fn get_size(&self) -> u64 {
+        self.0.get_size()
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        block_on(self.0.read(offset, size))
+    }
+
+    fn is_writable(&self) -> bool {
+        self.0.is_writable()
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        block_on(self.0.write(offset, data.to_vec()))
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), c_int> {
+        block_on(self.0.truncate(size))
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Poll `future` on the current thread until it completes, sleeping while it is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads that run jobs as they come in.
+pub(crate) struct WorkerPool {
+    jobs: Sender<Job>,
+}
+
+impl WorkerPool {
+    pub(crate) fn new(threads: usize) -> WorkerPool {
+        let (jobs, queue) = channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads.max(1) {
+            let queue = queue.clone();
+            std::thread::Builder::new()
+                .name(format!("fusible-worker-{i}"))
+                .spawn(move || loop {
+                    // The lock is only held while waiting for the next job, not while running it
+                    let job = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        // The pool was dropped
+                        Err(_) => return,
+                    };
+                    job();
+                })
+                .expect("failed to spawn worker thread");
+        }
+        WorkerPool { jobs }
+    }
+
+    pub(crate) fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        // Workers only stop once the pool is dropped, so this can't fail while we hold it
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{DirectoryListing, File};
+    use crate::testing::Harness;
+
+    #[derive(Debug)]
+    struct Countdown;
+
+    /// A future that needs to be polled a few times before it is ready.
+    struct Yield(u8);
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    impl AsyncFileHandler for Countdown {
+        fn get_size(&self) -> u64 {
+            3
+        }
+
+        fn read(&self, offset: u64, size: u32) -> BoxFuture<'_, Result<Vec<u8>, c_int>> {
+            Box::pin(async move {
+                Yield(3).await;
+                Ok(b"321"[offset as usize..][..size as usize].to_vec())
+            })
+        }
+    }
+
+    #[test]
+    fn async_files_can_be_read() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add("countdown", File::from_async(Countdown)));
+        assert_eq!(harness.read_to_end("/countdown"), Ok(b"321".to_vec()));
+    }
+
+    /// A file that names the thread reading it.
+    #[derive(Debug)]
+    struct WhichThread;
+
+    impl AsyncFileHandler for WhichThread {
+        fn get_size(&self) -> u64 {
+            64
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> BoxFuture<'_, Result<Vec<u8>, c_int>> {
+            let name = std::thread::current().name().unwrap_or_default().to_string();
+            Box::pin(async move { Ok(name.into_bytes()) })
+        }
+    }
+
+    #[test]
+    fn async_reads_go_to_the_worker_pool() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add("which", File::from_async(WhichThread)));
+        let ino = harness.resolve("/which").unwrap();
+        let (done, result) = channel();
+        harness.filesystem().dispatch_read(ino, 0, 0, 64, move |data| done.send(data).unwrap());
+        let name = String::from_utf8(result.recv().unwrap().unwrap()).unwrap();
+        assert!(name.starts_with("fusible-worker-"), "read on {name:?}");
+    }
+
+    #[derive(Debug)]
+    struct Buffer(Mutex<Vec<u8>>);
+
+    impl AsyncFileHandler for Buffer {
+        fn get_size(&self) -> u64 {
+            self.0.lock().unwrap().len() as u64
+        }
+
+        fn read(&self, offset: u64, size: u32) -> BoxFuture<'_, Result<Vec<u8>, c_int>> {
+            Box::pin(async move { Ok(self.0.lock().unwrap()[offset as usize..][..size as usize].to_vec()) })
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+
+        fn truncate(&self, size: u64) -> BoxFuture<'_, Result<(), c_int>> {
+            Box::pin(async move {
+                self.0.lock().unwrap().resize(size as usize, 0);
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn async_files_can_be_truncated() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add("buffer", File::from_async(Buffer(Mutex::new(b"old".to_vec())))));
+        let file = harness.open("/buffer", libc::O_WRONLY | libc::O_TRUNC).unwrap();
+        harness.release(file).unwrap();
+        // The kernel follows an `O_TRUNC` open with a `setattr` of the size
+        assert_eq!(harness.truncate("/buffer", 0).unwrap().size, 0);
+        assert_eq!(harness.truncate("/buffer", 2).unwrap().size, 2);
+    }
+
+    #[test]
+    fn worker_pool_runs_jobs() {
+        let pool = WorkerPool::new(2);
+        let (done, results) = channel();
+        for i in 0..4 {
+            let done = done.clone();
+            pool.spawn(move || done.send(block_on(async move { i * 2 })).unwrap());
+        }
+        let mut results: Vec<i32> = results.iter().take(4).collect();
+        results.sort();
+        assert_eq!(results, [0, 2, 4, 6]);
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use time::Timespec;
+
+/// How long the kernel may cache what it learns about an item before asking again.
+///
+/// By default nothing is cached, so every access sees the handler's current state.
+/// Content that never changes can use [`Ttl::IMMUTABLE`] to save the round trips.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use fusible::{RoutableFilesystem, cache::Ttl};
+/// let mut fs = RoutableFilesystem::new();
+/// fs.set_ttl(Ttl::new(Duration::from_secs(1), Duration::from_secs(10)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ttl {
+    /// How long attributes (size, permissions, timestamps) stay valid.
+    pub attr: Duration,
+    /// How long the result of looking up a name stays valid.
+    ///
+    /// `lookup` replies also carry the item's attributes,
+    /// and FUSE 0.3 has a single timeout for both, so those get this one.
+    pub entry: Duration,
+}
+
+impl Ttl {
+    /// Ask the handler every time.
+    pub const NONE: Ttl = Ttl::uniform(Duration::ZERO);
+
+    /// What files that declare themselves immutable get, unless they set their own.
+    pub const IMMUTABLE: Ttl = Ttl::uniform(Duration::from_secs(24 * 60 * 60));
+
+    pub const fn new(attr: Duration, entry: Duration) -> Self {
+        Ttl { attr, entry }
+    }
+
+    /// Use the same timeout for attributes and lookups.
+    pub const fn uniform(ttl: Duration) -> Self {
+        Ttl::new(ttl, ttl)
+    }
+
+    pub(crate) fn attr_timespec(&self) -> Timespec {
+        to_timespec(self.attr)
+    }
+
+    pub(crate) fn entry_timespec(&self) -> Timespec {
+        to_timespec(self.entry)
+    }
+}
+
+fn to_timespec(duration: Duration) -> Timespec {
+    Timespec::new(duration.as_secs() as i64, duration.subsec_nanos() as i32)
+}
+
+/// A handle for telling the filesystem that a file's size or content has changed.
+///
+/// Keep a clone in the handler (or wherever the data is updated)
+/// and hand it out from [`FileHandler::change_notifier`](crate::handler::FileHandler::change_notifier)
+/// or [`File::with_notifier`](crate::handler::File::with_notifier).
+///
+/// The `fuse` crate this is built on has no way to push invalidations to the kernel,
+/// so changes are picked up the next time the kernel asks:
+/// the file's mtime and ctime move to the time of the change and the next open drops the page cache,
+/// so `stat`-polling tools like `tail -f` see the update once the attribute [`Ttl`] runs out.
+/// The inode itself stays the same, so files that are already open keep working.
+/// In exchange, notified files keep the kernel's page cache between opens
+/// for as long as they have not changed, instead of rereading everything on every open.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeNotifier {
+    state: Arc<Mutex<ChangeState>>,
+}
+
+#[derive(Debug, Default)]
+struct ChangeState {
+    version: u64,
+    changed_at: Option<SystemTime>,
+}
+
+impl ChangeNotifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report that the file's size or content changed just now.
+    pub fn notify(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.version += 1;
+        state.changed_at = Some(SystemTime::now());
+    }
+
+    /// When [`notify`](ChangeNotifier::notify) was last called.
+    pub fn changed_at(&self) -> Option<SystemTime> {
+        self.state.lock().unwrap().changed_at
+    }
+
+    /// How many times [`notify`](ChangeNotifier::notify) has been called.
+    pub fn version(&self) -> u64 {
+        self.state.lock().unwrap().version
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use libc::c_int;
+
+    use super::*;
+    use crate::handler::{DirectoryListing, File, FileHandler};
+    use crate::testing::Harness;
+
+    #[derive(Debug)]
+    struct Fixed(bool);
+
+    impl FileHandler for Fixed {
+        fn get_size(&self) -> u64 {
+            0
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(Vec::new())
+        }
+
+        fn is_immutable(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn notified_files_keep_their_cache_until_they_change() {
+        let notifier = ChangeNotifier::new();
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add("live", File::from_impl(Fixed(false)).with_notifier(notifier.clone())));
+        let ino = harness.resolve("/live").unwrap();
+        let open = |harness: &mut Harness| harness.filesystem().do_open(ino, libc::O_RDONLY as u32).unwrap().1;
+
+        // The first open can't reuse anything, later ones can until there is a change
+        assert_eq!(open(&mut harness), 0);
+        assert_eq!(open(&mut harness), fuse::consts::FOPEN_KEEP_CACHE);
+        assert_eq!(harness.getattr("/live").unwrap().mtime, Timespec::new(0, 0));
+
+        notifier.notify();
+        assert_eq!(open(&mut harness), 0);
+        assert!(harness.getattr("/live").unwrap().mtime > Timespec::new(0, 0));
+        // A new generation would make the kernel fail reads on files that are already open
+        assert_eq!(harness.filesystem().generation_of(ino), 0);
+    }
+
+    #[test]
+    fn handler_ttls_take_precedence() {
+        let short = Ttl::uniform(Duration::from_millis(1500));
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_file("live", Fixed(false))
+            .add_file("frozen", Fixed(true))
+            .add("pinned", File::from_impl(Fixed(true)).with_ttl(short)));
+        harness.filesystem().set_ttl(Ttl::uniform(Duration::from_secs(1)));
+
+        let mut ttl_of = |path| {
+            let ino = harness.resolve(path).unwrap();
+            harness.filesystem().ttl_of(ino)
+        };
+        assert_eq!(ttl_of("/live"), Ttl::uniform(Duration::from_secs(1)));
+        assert_eq!(ttl_of("/frozen"), Ttl::IMMUTABLE);
+        assert_eq!(ttl_of("/pinned"), short);
+        assert_eq!(short.attr_timespec(), Timespec::new(1, 500_000_000));
+    }
+}
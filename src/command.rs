@@ -0,0 +1,279 @@
+//! Files backed by a script, like the ones in `/proc`.
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use fusible::{command::CommandFile, handler::DirectoryListing, testing::Harness};
+//! let root = DirectoryListing::new()
+//!     .add_file("uptime", CommandFile::new("cat").arg("/proc/uptime"))
+//!     .add_file("greeting", CommandFile::shell("echo \"hello, $NAME\"")
+//!         .env("NAME", "world")
+//!         .timeout(Duration::from_secs(1)));
+//! let mut harness = Harness::from_root(root);
+//! assert_eq!(harness.read_to_end("/greeting"), Ok(b"hello, world\n".to_vec()));
+//! ```
+
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libc::{c_int, EIO, ETIMEDOUT};
+use log::*;
+
+use crate::handler::{clamped, FileHandler, FileSession, Snapshot};
+
+/// How often to check whether a command with a timeout has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What to do when the command exits with a non-zero status or is killed by a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExitPolicy {
+    /// Fail the `open(2)` with `EIO`.
+    #[default]
+    Fail,
+    /// Serve whatever the command wrote to stdout anyway.
+    KeepOutput,
+}
+
+/// A file whose content is the standard output of a command,
+/// which runs again every time the file is opened.
+///
+/// Every open reads the output of the run it started, and `stat` reports the size of the latest run
+/// (or 0 before the first open, so that listing a directory doesn't run every command in it).
+/// The command's stderr is logged if it fails.
+#[derive(Debug)]
+pub struct CommandFile {
+    program: OsString,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    env_clear: bool,
+    current_dir: Option<PathBuf>,
+    timeout: Option<Duration>,
+    exit_policy: ExitPolicy,
+    /// The output of the latest run, or `None` if it has not run yet.
+    output: Mutex<Option<Vec<u8>>>,
+}
+
+impl CommandFile {
+    /// Run `program`, looked up in `PATH` like [`Command::new`] does.
+    pub fn new(program: impl AsRef<OsStr>) -> CommandFile {
+        CommandFile {
+            program: program.as_ref().to_os_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+            env_clear: false,
+            current_dir: None,
+            timeout: None,
+            exit_policy: ExitPolicy::default(),
+            output: Mutex::new(None),
+        }
+    }
+
+    /// Run `script` with `sh -c`.
+    pub fn shell(script: &str) -> CommandFile {
+        CommandFile::new("sh").arg("-c").arg(script)
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(mut self, args: I) -> Self {
+        self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Set an environment variable for the command.
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+        self.env.push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
+    /// Don't pass on the filesystem's own environment, only what is set with [`env`](CommandFile::env).
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self
+    }
+
+    /// Run the command in `dir` instead of the filesystem's working directory.
+    pub fn current_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Kill the command if it runs for longer than `timeout`, and fail the open with `ETIMEDOUT`.
+    ///
+    /// Without a timeout, opening the file waits for as long as the command runs.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn exit_policy(mut self, policy: ExitPolicy) -> Self {
+        self.exit_policy = policy;
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if self.env_clear {
+            command.env_clear();
+        }
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    /// Run the command and return its stdout, or the errno to fail with.
+    fn run(&self) -> Result<Vec<u8>, c_int> {
+        let program = self.program.to_string_lossy();
+        let mut child = self.command().spawn().map_err(|err| {
+            error!("Failed to run {program}: {err}");
+            EIO
+        })?;
+
+        // Drain the pipes while waiting, so a command with a lot of output doesn't block on them
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let status = match wait(&mut child, self.timeout) {
+            Ok(Some(status)) => status,
+            Ok(None) => {
+                error!("{program} did not finish within {:?}, killed it", self.timeout.unwrap_or_default());
+                return Err(ETIMEDOUT);
+            }
+            Err(err) => {
+                error!("Failed to wait for {program}: {err}");
+                return Err(EIO);
+            }
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        if !status.success() {
+            warn!("{program} exited with {status}: {}", String::from_utf8_lossy(&stderr).trim_end());
+            if self.exit_policy == ExitPolicy::Fail {
+                return Err(EIO);
+            }
+        }
+        Ok(stdout)
+    }
+
+    fn with_output<T>(&self, f: impl FnOnce(&[u8]) -> T) -> Result<T, c_int> {
+        let mut output = self.output.lock().unwrap();
+        let output = match &mut *output {
+            Some(output) => output,
+            // Only reads that come without an open get here
+            empty => empty.insert(self.run()?),
+        };
+        Ok(f(output))
+    }
+}
+
+/// Read everything from a pipe on a separate thread.
+fn drain(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut content = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut content);
+        }
+        content
+    })
+}
+
+/// Wait for `child` to exit, killing it if it takes longer than `timeout`.
+///
+/// Returns `None` if it timed out.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> std::io::Result<Option<std::process::ExitStatus>> {
+    let deadline = match timeout {
+        Some(timeout) => Instant::now() + timeout,
+        None => return child.wait().map(Some),
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl FileHandler for CommandFile {
+    fn get_size(&self) -> u64 {
+        self.output.lock().unwrap().as_ref().map_or(0, |output| output.len() as u64)
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.with_output(|output| clamped(output, offset, size).to_vec())
+    }
+
+    fn open(&self, _flags: u32) -> Result<Option<Box<dyn FileSession>>, c_int> {
+        let output = self.run()?;
+        *self.output.lock().unwrap() = Some(output.clone());
+        Ok(Some(Box::new(Snapshot::new(output))))
+    }
+
+    fn is_direct_io(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::DirectoryListing;
+    use crate::testing::Harness;
+
+    #[test]
+    fn commands_run_on_every_open() {
+        let dir = std::env::temp_dir();
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_file("count", CommandFile::shell("echo x >> \"$LOG\"; wc -l < \"$LOG\"")
+                .env("LOG", format!("fusible-command-{}", std::process::id()))
+                .current_dir(&dir)));
+        // Nothing runs until the file is opened
+        assert_eq!(harness.getattr("/count").unwrap().size, 0);
+        assert!(!dir.join(format!("fusible-command-{}", std::process::id())).exists());
+        let mut count = || {
+            let output = harness.read_to_end("/count").unwrap();
+            String::from_utf8(output).unwrap().trim().parse::<u32>().unwrap()
+        };
+        let first = count();
+        assert_eq!(count(), first + 1);
+        assert_eq!(harness.getattr("/count").unwrap().size, 2);
+        std::fs::remove_file(dir.join(format!("fusible-command-{}", std::process::id()))).unwrap();
+    }
+
+    #[test]
+    fn failures_follow_the_policy() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_file("fail", CommandFile::shell("echo partial; exit 3"))
+            .add_file("keep", CommandFile::shell("echo partial; exit 3").exit_policy(ExitPolicy::KeepOutput))
+            .add_file("slow", CommandFile::new("sleep").arg("5").timeout(Duration::from_millis(50))));
+        assert_eq!(harness.read_to_end("/fail"), Err(EIO));
+        assert_eq!(harness.read_to_end("/keep"), Ok(b"partial\n".to_vec()));
+        assert_eq!(harness.read_to_end("/slow"), Err(ETIMEDOUT));
+    }
+    #[test]
+    fn reads_are_clamped_to_the_latest_output() {
+        let file = CommandFile::shell("echo hi");
+        assert!(file.open(0).is_ok());
+        // The output may have shrunk since the kernel clamped the read to the size it saw
+        assert_eq!(file.read(1, 10), Ok(b"i\n".to_vec()));
+        assert_eq!(file.read(10, 4), Ok(Vec::new()));
+    }
+}
@@ -1,6 +1,9 @@
 use std::ffi::{OsStr, c_int};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use libc::{ENOSYS, ENOENT, ENOTDIR};
+use libc::{ENOENT, ENOTDIR, EISDIR, EACCES, EINVAL, ENODATA, ENOTSUP, ERANGE, EROFS, EXDEV, EIO, O_ACCMODE, O_RDONLY, O_TRUNC, O_WRONLY, R_OK, W_OK};
 use trace::trace;
 
 use log::*;
@@ -9,191 +12,998 @@ trace::init_depth_var!();
 
 
 use fuse::{Filesystem, FileType};
+use fuse::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
 use time::Timespec;
 
-use crate::{handler::{DirectoryListing, PathHandler}, identity::ItemIdentity};
+use serde::{Deserialize, Serialize};
+
+use crate::access::{self, AccessPolicy, Operation, RequestContext};
+#[cfg(feature = "async")]
+use crate::async_handler::{block_on, WorkerPool};
+use crate::cache::Ttl;
+use crate::{handler::{DirectoryListing, File, FileSession, MutableDirectory, PathHandler}, identity::{IdentityScheme, ItemIdentity}};
 use crate::handler::Identifiable;
+use crate::metadata::to_timespec;
+use crate::mount::{MountError, MountHandle, MountOptions};
+use crate::signal::Shutdown;
+use crate::statfs::StatFs;
+use crate::tree::{self, ChangeKind, SharedTree, TreeHandle};
+
+/// How many threads answer reads and writes on async files, unless set otherwise.
+#[cfg(feature = "async")]
+const DEFAULT_WORKERS: usize = 4;
+
+/// The path of the item `name` in the directory at `parent_path`.
+fn child_path(parent_path: &str, name: &str) -> String {
+    if parent_path == "/" {
+        format!("/{name}")
+    } else {
+        format!("{parent_path}/{name}")
+    }
+}
+
+/// What to send back for `getxattr` or `listxattr`.
+#[derive(Debug, PartialEq)]
+enum XattrReply<'v> {
+    /// The length of the value, when the caller is asking how big a buffer it needs.
+    Size(u32),
+    Data(&'v [u8]),
+}
+
+/// Decide how to answer a `getxattr` or `listxattr` with a buffer of `size` bytes.
+/// 
+/// A `size` of zero asks only for the length of the value;
+/// otherwise the value must fit in `size` bytes.
+fn xattr_reply(value: &[u8], size: u32) -> Result<XattrReply<'_>, c_int> {
+    if size == 0 {
+        Ok(XattrReply::Size(value.len() as u32))
+    } else if value.len() > size as usize {
+        Err(ERANGE)
+    } else {
+        Ok(XattrReply::Data(value))
+    }
+}
+
+/// The identity-to-inode table as it is saved to disk.
+#[derive(Serialize, Deserialize)]
+struct InodeTable {
+    latest_ino: u64,
+    inodes: Vec<(ItemIdentity, u64)>,
+}
+
 pub struct RoutableFilesystem<'a> {
-    ino_to_path: std::collections::HashMap<u64, String>,
-    path_to_ino: std::collections::HashMap<String, u64>,
-    ino_to_handler: std::collections::HashMap<u64, PathHandler<'a>>,
-    ino_parent: std::collections::HashMap<u64, u64>,
+    pub(crate) ino_to_path: std::collections::HashMap<u64, String>,
+    pub(crate) path_to_ino: std::collections::HashMap<String, u64>,
+    pub(crate) ino_to_handler: std::collections::HashMap<u64, PathHandler<'a>>,
+    pub(crate) ino_parent: std::collections::HashMap<u64, u64>,
     identity_to_ino: std::collections::HashMap<ItemIdentity, u64>,
+    /// Every inode number that belongs to an identity, so no two identities get the same one.
+    taken_inos: std::collections::HashSet<u64>,
+    /// The identity each inode was given, which the identities of items rebuilt inside it derive from.
+    ino_to_identity: std::collections::HashMap<u64, ItemIdentity>,
+    /// Inodes of items in or below a dynamic directory, which get a fresh handler on every request.
+    rebuilt_inos: std::collections::HashSet<u64>,
     latest_ino: u64,
+    identity_scheme: IdentityScheme,
+    inode_table_path: Option<PathBuf>,
+    ttl: Ttl,
+    /// Sessions of open files, by file handle.
+    pub(crate) sessions: std::collections::HashMap<u64, Box<dyn FileSession>>,
+    next_fh: u64,
+    /// The change version of each notified file as of its latest open.
+    opened_versions: std::collections::HashMap<u64, u64>,
+    /// Threads that answer reads and writes on async files, started on first use.
+    #[cfg(feature = "async")]
+    workers: Option<WorkerPool>,
+    #[cfg(feature = "async")]
+    worker_count: usize,
+
+    /// Where `statfs` gets its numbers, instead of totalling the tree.
+    statfs: Option<Box<dyn Fn() -> StatFs + Send + Sync + 'a>>,
+    /// The tree of items, shared with any [`TreeHandle`]s.
+    tree: Arc<SharedTree<'a>>,
 
-    root: DirectoryListing<'a>,
+    /// The process that made the request being handled.
+    pub(crate) request: RequestContext,
+    access_policy: Option<AccessPolicy<'a>>,
 }
 
 impl<'a> RoutableFilesystem<'a> {
     fn get_ino_by_identity(&mut self, identity: ItemIdentity) -> u64 {
         if let Some(ino) = self.identity_to_ino.get(&identity) {
-            *ino
-        } else {
-            self.identity_to_ino.insert(identity, self.latest_ino);
-            self.latest_ino += 1;
-            self.latest_ino - 1
+            return *ino;
         }
+        let ino = match identity.preferred_ino() {
+            // Stable identities get the inode derived from them, or the next free one if another identity has it
+            Some(mut ino) => {
+                while ino <= fuse::FUSE_ROOT_ID || self.taken_inos.contains(&ino) {
+                    ino = ino.wrapping_add(1);
+                }
+                ino
+            }
+            // The rest are numbered in the order they are seen
+            None => {
+                while self.taken_inos.contains(&self.latest_ino) {
+                    self.latest_ino += 1;
+                }
+                self.latest_ino += 1;
+                self.latest_ino - 1
+            }
+        };
+        self.identity_to_ino.insert(identity, ino);
+        self.taken_inos.insert(ino);
+        ino
     }
-}
 
-impl<'a> Filesystem for RoutableFilesystem<'a> {
-    #[trace]
-    fn init(&mut self, _req: &fuse::Request) -> Result<(), c_int> {
-        Ok(())
+    /// Whether the item in the directory `parent` is rebuilt on every request,
+    /// because `parent` is a dynamic directory or is itself inside one.
+    fn is_rebuilt(&self, parent: u64) -> bool {
+        self.rebuilt_inos.contains(&parent) || matches!(self.ino_to_handler.get(&parent), Some(PathHandler::DynamicDirectory(_)))
     }
 
-    fn readdir(&mut self, _req: &fuse::Request, ino: u64, _fh: u64, offset: i64, mut reply: fuse::ReplyDirectory) {
-        // If the ino doesn't have a handler, return ENOENT
-        if !self.ino_to_handler.contains_key(&ino) {
-            reply.error(ENOENT);
-            return;
+    /// The identity of the item at `path` in the directory `parent`, which decides its inode.
+    fn identity_of(&self, parent: u64, path: &str, handler: &PathHandler<'a>) -> ItemIdentity {
+        // A user-supplied key always wins.
+        // Otherwise, items in a dynamic directory are rebuilt on every request, and so is everything below them,
+        // so their identity has to come from where they are instead of from the handler.
+        // The parent's handler may have been rebuilt too, so this goes by the identity that gave it its inode
+        let identity = handler.get_identity();
+        match self.ino_to_identity.get(&parent) {
+            _ if identity.is_stable() => identity,
+            _ if self.identity_scheme == IdentityScheme::Path => ItemIdentity::from_path(path),
+            Some(parent_identity) if self.is_rebuilt(parent) => {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                parent_identity.child(name)
+            }
+            _ => identity,
         }
+    }
 
-        // If the ino has a handler, but it's not a directory, return ENOTDIR
-        let dir_handler = match self.ino_to_handler.get(&ino).unwrap() {
-            PathHandler::Directory(handler) => handler.clone(),
-            _ => {
-                reply.error(ENOTDIR);
-                return;
-            }
-        };
+    /// Assign an inode to an item found in the directory `parent`,
+    /// and record its handler, parent and path for later requests.
+    fn register_child(&mut self, parent: u64, name: &str, handler: PathHandler<'a>) -> u64 {
+        let parent_path = self.ino_to_path.get(&parent).cloned().unwrap_or_default();
+        let path = child_path(&parent_path, name);
+        let identity = self.identity_of(parent, &path, &handler);
+        let ino = self.get_ino_by_identity(identity);
+        if self.is_rebuilt(parent) {
+            self.rebuilt_inos.insert(ino);
+        }
 
-        let parent_ino = self.ino_parent.get(&ino).unwrap();
+        self.ino_to_identity.insert(ino, identity);
+        self.ino_to_handler.insert(ino, handler);
+        self.ino_parent.insert(ino, parent);
+        self.ino_to_path.insert(ino, path.clone());
+        self.path_to_ino.insert(path, ino);
+        ino
+    }
 
-        let mut entries = vec![
-            (ino, FileType::Directory, ".".to_string()),
-            (*parent_ino, FileType::Directory, "..".to_string()),
-        ];
+    /// Catch up on changes made through [`TreeHandle`]s since the last request.
+    fn sync_tree(&mut self) {
+        let tree = self.tree.clone();
+        let mut state = match tree.take_changes() {
+            Some(state) => state,
+            None => return,
+        };
+        let changes = std::mem::take(&mut state.changes);
 
-        for (name, handler) in dir_handler.listdir() {
-            let ino = self.get_ino_by_identity(handler.get_identity());
-            entries.push((ino, handler.get_type(), name));
+        for (path, kind) in &changes {
+            match kind {
+                ChangeKind::Inserted => {}
+                ChangeKind::Removed => self.forget(path, true),
+                ChangeKind::Replaced => {
+                    // The new item takes over the inode, but nothing that was below the old one
+                    self.forget(path, false);
+                    if let (Some(&ino), Some(item)) = (self.path_to_ino.get(path), state.get(path)) {
+                        self.identity_to_ino.insert(item.get_identity(), ino);
+                        self.ino_to_identity.insert(ino, item.get_identity());
+                        self.ino_to_handler.insert(ino, item.clone());
+                    }
+                }
+            }
         }
 
-        for (i, (ino, ty, name)) in entries.iter().enumerate().skip(offset as usize) {
-            if reply.add(*ino, (i + 1) as i64, *ty, OsStr::new(name)) {
-                break;
+        // Directories hold copies of everything below them, so the ones leading to a change are stale too
+        for (path, _) in &changes {
+            let names = tree::components(path);
+            for depth in 0..names.len() {
+                let ancestor = format!("/{}", names[..depth].join("/"));
+                if let (Some(&ino), Some(item)) = (self.path_to_ino.get(&ancestor), state.get(&ancestor)) {
+                    self.ino_to_handler.insert(ino, item.clone());
+                }
             }
         }
+    }
 
-        reply.ok();
-        
+    /// Drop the inodes below `path`, and of `path` itself if `including_path`, from the path and handler maps.
+    /// 
+    /// Identities keep their inode numbers, so an item that comes back gets the same one.
+    fn forget(&mut self, path: &str, including_path: bool) {
+        let below = format!("{path}/");
+        let forgotten: Vec<u64> = self.ino_to_path.iter()
+            .filter(|(_, item_path)| item_path.starts_with(&below) || (including_path && *item_path == path))
+            .map(|(ino, _)| *ino)
+            .collect();
+        for ino in forgotten {
+            if let Some(item_path) = self.ino_to_path.remove(&ino) {
+                self.path_to_ino.remove(&item_path);
+            }
+            self.ino_to_handler.remove(&ino);
+            self.ino_to_identity.remove(&ino);
+            self.rebuilt_inos.remove(&ino);
+            self.ino_parent.remove(&ino);
+            self.opened_versions.remove(&ino);
+        }
     }
 
-    #[trace]
-    fn getattr(&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyAttr) {
+    /// Reply to `getxattr` or `listxattr`.
+    fn reply_xattr(value: &[u8], size: u32, reply: fuse::ReplyXattr) {
+        match xattr_reply(value, size) {
+            Ok(XattrReply::Size(len)) => reply.size(len),
+            Ok(XattrReply::Data(value)) => reply.data(value),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Build the attributes reported for the item with the given inode.
+    /// 
+    /// Anything the item's metadata leaves unset gets a permissive default.
+    fn get_attr(ino: u64, handler: &PathHandler) -> fuse::FileAttr {
+        let metadata = handler.metadata();
+        let time = |time: Option<SystemTime>| time.map(to_timespec).unwrap_or(Timespec::new(0, 0));
         let mut attr = fuse::FileAttr {
-            ino: ino,
+            ino,
             size: 0,
             blocks: 0,
-            atime: Timespec::new(0, 0),
-            mtime: Timespec::new(0, 0),
-            ctime: Timespec::new(0, 0),
-            crtime: Timespec::new(0, 0),
+            atime: time(metadata.atime),
+            mtime: time(metadata.mtime),
+            ctime: time(metadata.ctime),
+            crtime: time(metadata.crtime),
             kind: FileType::Directory,
-            perm: 0o755,
+            perm: metadata.perm.unwrap_or(0o755),
             nlink: 0,
-            uid: 0,
-            gid: 0,
+            uid: metadata.uid.unwrap_or(0),
+            gid: metadata.gid.unwrap_or(0),
             rdev: 0,
-            flags: 0,
-        };
-
-        let handler = match self.ino_to_handler.get(&ino) {
-            Some(handler) => handler,
-            None => {
-                info!("getattr: no handler for ino {}", ino);
-                reply.error(ENOENT);
-                return;
-            }
+            flags: metadata.flags.unwrap_or(0),
         };
 
         match handler {
-            PathHandler::Directory(_) => {
+            PathHandler::Directory(_) | PathHandler::DynamicDirectory(_) => {
                 attr.size = 4096;
                 attr.blocks = 8;
                 attr.kind = FileType::Directory;
-                attr.perm = 0o755;
-                attr.nlink = 2;
+                attr.nlink = metadata.nlink.unwrap_or(2);
             },
             PathHandler::File(file) => {
                 attr.size = file.get_size();
                 attr.blocks = file.get_size() / 512;
                 attr.kind = FileType::RegularFile;
-                attr.perm = 0o755;
-                attr.nlink = 1;
+                attr.nlink = metadata.nlink.unwrap_or(1);
+            },
+            PathHandler::Symlink(link) => {
+                attr.size = link.target().len() as u64;
+                attr.kind = FileType::Symlink;
+                attr.perm = metadata.perm.unwrap_or(0o777);
+                attr.nlink = metadata.nlink.unwrap_or(1);
+            }
+        }
+        attr
+    }
+}
+
+/// An entry returned by [`RoutableFilesystem`]'s directory listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+/// The operations below do the actual work behind the [`Filesystem`] impl.
+/// They return errno values instead of replying to the kernel,
+/// so that [`testing::Harness`](crate::testing::Harness) can call them directly.
+impl<'a> RoutableFilesystem<'a> {
+    pub(crate) fn do_readdir(&mut self, ino: u64) -> Result<Vec<DirEntry>, c_int> {
+        self.sync_tree();
+        // If the ino has a handler, but it's not a directory, return ENOTDIR
+        let listing = match self.ino_to_handler.get(&ino) {
+            Some(PathHandler::Directory(handler)) => handler.listdir(),
+            Some(PathHandler::DynamicDirectory(handler)) => handler.list_as(&self.request),
+            Some(_) => return Err(ENOTDIR),
+            None => {
+                info!("readdir: no handler for ino {ino}");
+                return Err(ENOENT);
+            }
+        };
+
+        self.check_access(ino, Operation::Read)?;
+        let parent_ino = *self.ino_parent.get(&ino).unwrap();
+        let path = self.ino_to_path.get(&ino).cloned().unwrap_or_default();
+
+        let mut entries = vec![
+            DirEntry { ino, kind: FileType::Directory, name: ".".to_string() },
+            DirEntry { ino: parent_ino, kind: FileType::Directory, name: "..".to_string() },
+        ];
+
+        for (name, handler) in listing {
+            // Items that can't be looked up shouldn't show up either
+            if !self.allows(&child_path(&path, &name), Operation::Lookup) {
+                continue;
+            }
+            let kind = handler.get_type();
+            let child_ino = self.register_child(ino, &name, handler);
+            entries.push(DirEntry { ino: child_ino, kind, name });
+        }
+        Ok(entries)
+    }
+
+    /// How long the kernel may cache what it learns about the item with the given inode.
+    pub(crate) fn ttl_of(&self, ino: u64) -> Ttl {
+        self.ino_to_handler.get(&ino).and_then(|handler| handler.ttl()).unwrap_or(self.ttl)
+    }
+
+    /// The generation of the inode, which never changes because inode numbers are never reused.
+    ///
+    /// The kernel marks an inode it already knows as stale if a lookup gives it a new generation,
+    /// which fails every read on files that are already open, so changes must not touch this.
+    pub(crate) fn generation_of(&self, _ino: u64) -> u64 {
+        0
+    }
+
+    /// Whether the access policy lets the current request do `operation` on `path`.
+    fn allows(&self, path: &str, operation: Operation) -> bool {
+        match &self.access_policy {
+            Some(policy) => policy(&self.request, path, operation),
+            None => true,
+        }
+    }
+
+    /// Fail with `EACCES` unless the access policy lets the current request do `operation` on `ino`.
+    fn check_access(&self, ino: u64, operation: Operation) -> Result<(), c_int> {
+        let path = self.ino_to_path.get(&ino).map(String::as_str).unwrap_or_default();
+        if self.allows(path, operation) {
+            Ok(())
+        } else {
+            info!("{operation:?} on {path} denied to uid {}", self.request.uid);
+            Err(EACCES)
+        }
+    }
+
+    pub(crate) fn do_getattr(&mut self, ino: u64) -> Result<fuse::FileAttr, c_int> {
+        self.sync_tree();
+        match self.ino_to_handler.get(&ino) {
+            Some(handler) => Ok(Self::get_attr(ino, handler)),
+            None => {
+                info!("getattr: no handler for ino {}", ino);
+                Err(ENOENT)
             }
         }
-        reply.attr(&Timespec::new(0, 0), &attr);
+    }
+
+    pub(crate) fn do_lookup(&mut self, parent: u64, name: &str) -> Result<fuse::FileAttr, c_int> {
+        self.sync_tree();
+        let parent_path = self.ino_to_path.get(&parent).cloned().unwrap_or_default();
+        if !self.allows(&child_path(&parent_path, name), Operation::Lookup) {
+            return Err(EACCES);
+        }
 
+        // Find the item in the directory
+        let found = match self.ino_to_handler.get(&parent) {
+            Some(PathHandler::Directory(handler)) => handler.get(name),
+            Some(PathHandler::DynamicDirectory(handler)) => handler.lookup_as(&self.request, name),
+            Some(_) => return Err(ENOTDIR),
+            None => {
+                info!("lookup: no handler for parent ino {parent}");
+                return Err(ENOENT);
+            }
+        };
+        let handler = found.ok_or(ENOENT)?;
+
+        // Remember the item so that later calls on its inode can find it
+        let ino = self.register_child(parent, name, handler.clone());
+        Ok(Self::get_attr(ino, &handler))
+    }
+
+    /// Find the file behind `ino`, for operations that only make sense on files.
+    fn get_handler(&self, ino: u64, op: &str) -> Result<&PathHandler<'a>, c_int> {
+        self.ino_to_handler.get(&ino).ok_or_else(|| {
+            info!("{op}: no handler for ino {ino}");
+            ENOENT
+        })
     }
 
-    fn lookup(&mut self, _req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEntry) {
-        // Check if the parent is a directory
-        if !self.ino_to_handler.contains_key(&parent) {
-            info!("lookup: no handler for parent ino {parent}");
-            reply.error(ENOENT);
+    fn get_file(&self, ino: u64, op: &str) -> Result<&File<'a>, c_int> {
+        match self.ino_to_handler.get(&ino) {
+            Some(PathHandler::File(file)) => Ok(file),
+            Some(_) => Err(EISDIR),
+            None => {
+                info!("{op}: no handler for ino {ino}");
+                Err(ENOENT)
+            }
+        }
+    }
+
+    /// Read through the file handle `fh`, which goes to the open's session if it has one.
+    pub(crate) fn do_read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.sync_tree();
+        self.read_synced(ino, fh, offset, size)
+    }
+
+    /// Answer a read through `reply`, from a worker thread if the file is async.
+    pub(crate) fn dispatch_read(&mut self, ino: u64, fh: u64, offset: u64, size: u32, reply: impl FnOnce(Result<Vec<u8>, c_int>) + Send + 'static) {
+        self.sync_tree();
+        #[cfg(feature = "async")]
+        if let Some((handler, size)) = self.get_file(ino, "read").ok().and_then(|file| Some((file.async_handler()?, file.clamp_read(offset, size)))) {
+            match size {
+                Some(size) => self.workers().spawn(move || reply(block_on(handler.read(offset, size)))),
+                None => reply(Ok(Vec::new())),
+            }
             return;
         }
-        let dirhandler = match self.ino_to_handler.get(&parent).unwrap() {
-            PathHandler::Directory(handler) => handler.clone(),
-            _ => {
-                reply.error(ENOTDIR);
-                return;
+        reply(self.read_synced(ino, fh, offset, size))
+    }
+
+    fn read_synced(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        if let Some(session) = self.sessions.get_mut(&fh) {
+            return session.read(offset, size);
+        }
+        self.get_file(ino, "read")?.read_as(&self.request, offset, size)
+    }
+
+    pub(crate) fn do_readlink(&mut self, ino: u64) -> Result<String, c_int> {
+        self.sync_tree();
+        let handler = self.get_handler(ino, "readlink")?;
+        self.check_access(ino, Operation::Read)?;
+        match handler {
+            PathHandler::Symlink(link) => Ok(link.target()),
+            _ => Err(EINVAL),
+        }
+    }
+
+    pub(crate) fn do_getxattr(&mut self, ino: u64, name: &str) -> Result<Vec<u8>, c_int> {
+        self.sync_tree();
+        let handler = self.get_handler(ino, "getxattr")?;
+        self.check_access(ino, Operation::Read)?;
+        handler.get_xattr(name).ok_or(ENODATA)
+    }
+
+    pub(crate) fn do_listxattr(&mut self, ino: u64) -> Result<Vec<String>, c_int> {
+        self.sync_tree();
+        let handler = self.get_handler(ino, "listxattr")?;
+        self.check_access(ino, Operation::Read)?;
+        Ok(handler.list_xattrs())
+    }
+
+    pub(crate) fn do_setxattr(&mut self, ino: u64, name: &str, value: &[u8]) -> Result<(), c_int> {
+        self.sync_tree();
+        let handler = self.get_handler(ino, "setxattr")?;
+        self.check_access(ino, Operation::Write)?;
+        match handler {
+            PathHandler::File(file) => file.set_xattr(name, value),
+            _ => Err(ENOTSUP),
+        }
+    }
+
+    pub(crate) fn do_removexattr(&mut self, ino: u64, name: &str) -> Result<(), c_int> {
+        self.sync_tree();
+        let handler = self.get_handler(ino, "removexattr")?;
+        self.check_access(ino, Operation::Write)?;
+        match handler {
+            PathHandler::File(file) => file.remove_xattr(name),
+            _ => Err(ENOTSUP),
+        }
+    }
+
+    /// Open a file, returning the file handle and the `FOPEN_*` flags for the reply.
+    pub(crate) fn do_open(&mut self, ino: u64, flags: u32) -> Result<(u64, u32), c_int> {
+        self.sync_tree();
+        let file = self.get_file(ino, "open")?;
+
+        // Opening for writing only makes sense if the handler can take writes
+        let wants_read = flags as i32 & O_ACCMODE != O_WRONLY;
+        let wants_write = flags as i32 & O_ACCMODE != O_RDONLY || flags as i32 & O_TRUNC != 0;
+        if wants_write && !file.is_writable() {
+            return Err(EACCES);
+        }
+        if wants_read {
+            self.check_access(ino, Operation::Read)?;
+        }
+        if wants_write {
+            self.check_access(ino, Operation::Write)?;
+        }
+
+        let session = file.open_as(&self.request, flags)?;
+        let direct_io = file.is_direct_io();
+        let immutable = file.is_immutable();
+
+        // Notified files are unchanged if they haven't reported anything since they were last opened
+        let unchanged = match file.change_notifier() {
+            Some(notifier) => {
+                let version = notifier.version();
+                self.opened_versions.insert(ino, version) == Some(version)
+            }
+            None => false,
+        };
+
+        // Unchanged files can keep what the kernel has already read from earlier opens
+        let open_flags = if direct_io {
+            FOPEN_DIRECT_IO
+        } else if immutable || unchanged {
+            FOPEN_KEEP_CACHE
+        } else {
+            0
+        };
+
+        // Only opens with a session need a file handle; the rest all share 0
+        let fh = match session {
+            Some(session) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.sessions.insert(fh, session);
+                fh
             }
+            None => 0,
         };
+        Ok((fh, open_flags))
+    }
 
-        // Get the directory listing and find the item's identity
-        let listing: Vec<(String, &PathHandler)> = dirhandler.listdir();
-        let handler = match listing.iter().find(|(n, _)| n == name.to_str().unwrap()) {
-            Some((_, handler)) => handler,
+    pub(crate) fn do_write(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        self.sync_tree();
+        self.write_synced(ino, fh, offset, data)
+    }
+
+    /// Answer a write through `reply`, from a worker thread if the file is async.
+    pub(crate) fn dispatch_write(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8], reply: impl FnOnce(Result<u32, c_int>) + Send + 'static) {
+        self.sync_tree();
+        #[cfg(feature = "async")]
+        if let Some(handler) = self.get_file(ino, "write").ok().and_then(|file| file.async_handler()) {
+            let data = data.to_vec();
+            self.workers().spawn(move || reply(block_on(handler.write(offset, data))));
+            return;
+        }
+        reply(self.write_synced(ino, fh, offset, data))
+    }
+
+    fn write_synced(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        if let Some(session) = self.sessions.get_mut(&fh) {
+            return session.write(offset, data);
+        }
+        self.get_file(ino, "write")?.write(offset, data)
+    }
+
+    pub(crate) fn do_flush(&mut self, _ino: u64, fh: u64) -> Result<(), c_int> {
+        match self.sessions.get_mut(&fh) {
+            Some(session) => session.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Close the file handle `fh`, dropping its session.
+    pub(crate) fn do_release(&mut self, _ino: u64, fh: u64) -> Result<(), c_int> {
+        match self.sessions.remove(&fh) {
+            Some(mut session) => session.release(),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn do_statfs(&mut self) -> StatFs {
+        self.sync_tree();
+        match &self.statfs {
+            Some(provider) => provider(),
             None => {
-                reply.error(ENOENT);
-                return;
+                // Sizing files can render them, so writers to the tree aren't held up meanwhile
+                let mut files = Vec::new();
+                let (items, writable) = {
+                    let tree = self.tree.lock();
+                    (StatFs::collect_files(&tree.root, &mut files), tree.root.is_writable())
+                };
+                StatFs::of_tree(&files, items, writable)
+            }
+        }
+    }
+
+    /// Check whether the current request may access `ino` as asked by the `access(2)` `mask`.
+    ///
+    /// This goes by the access policy, whether the item takes writes, and its reported mode and owner.
+    pub(crate) fn do_access(&mut self, ino: u64, mask: i32) -> Result<(), c_int> {
+        self.sync_tree();
+        let handler = match self.ino_to_handler.get(&ino) {
+            Some(handler) => handler,
+            None => {
+                info!("access: no handler for ino {ino}");
+                return Err(ENOENT);
             }
         };
-        let attrs = match handler {
-            PathHandler::File(fhandler) => {
-                let ino = self.get_ino_by_identity(handler.get_identity());
-                fuse::FileAttr {
-                    ino: ino,
-                    size: fhandler.get_size(),
-                    blocks: fhandler.get_size() / 512,
-                    atime: Timespec::new(0, 0),
-                    mtime: Timespec::new(0, 0),
-                    ctime: Timespec::new(0, 0),
-                    crtime: Timespec::new(0, 0),
-                    kind: FileType::RegularFile,
-                    perm: 0o755,
-                    nlink: 0,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                    flags: 0,
+        // Unlike for the mount mode, writable files further down don't make a directory writable:
+        // writing to a directory means creating or removing items in it
+        let writable = match handler {
+            PathHandler::File(file) => file.is_writable(),
+            PathHandler::DynamicDirectory(dir) => dir.mutable().is_some(),
+            PathHandler::Directory(_) | PathHandler::Symlink(_) => false,
+        };
+        if mask & W_OK != 0 && !writable {
+            return Err(EACCES);
+        }
+        let attr = Self::get_attr(ino, handler);
+        if !access::mode_allows(&attr, &self.request, mask) {
+            return Err(EACCES);
+        }
+        if mask & R_OK != 0 {
+            self.check_access(ino, Operation::Read)?;
+        }
+        if mask & W_OK != 0 {
+            self.check_access(ino, Operation::Write)?;
+        }
+        Ok(())
+    }
+
+    /// Find the directory behind `ino`, for operations that create or remove items in it.
+    fn get_mutable_dir(&self, ino: u64, op: &str) -> Result<Arc<dyn MutableDirectory<'a> + 'a>, c_int> {
+        match self.ino_to_handler.get(&ino) {
+            Some(PathHandler::DynamicDirectory(dir)) => dir.mutable().ok_or(EROFS),
+            Some(PathHandler::Directory(_)) => Err(EROFS),
+            Some(_) => Err(ENOTDIR),
+            None => {
+                info!("{op}: no handler for ino {ino}");
+                Err(ENOENT)
+            }
+        }
+    }
+
+    /// Create the file `name` in `parent` and open it,
+    /// returning its attributes, the file handle and the `FOPEN_*` flags for the reply.
+    pub(crate) fn do_create(&mut self, parent: u64, name: &str, mode: u32, flags: u32) -> Result<(fuse::FileAttr, u64, u32), c_int> {
+        self.sync_tree();
+        let dir = self.get_mutable_dir(parent, "create")?;
+        self.check_access(parent, Operation::Write)?;
+        let handler = PathHandler::File(dir.create(&self.request, name, mode)?);
+        let ino = self.register_child(parent, name, handler.clone());
+        match self.do_open(ino, flags) {
+            Ok((fh, open_flags)) => Ok((Self::get_attr(ino, &handler), fh, open_flags)),
+            Err(errno) => {
+                // Whether the new file can be opened as asked is only known once it exists,
+                // so take it away again rather than leave behind a file the caller never got
+                if let Err(err) = dir.unlink(&self.request, name) {
+                    warn!("create: failed to remove {name} after its open failed: errno {err}");
                 }
-            },
-            PathHandler::Directory(dhandler) => fuse::FileAttr {
-                ino: self.get_ino_by_identity(handler.get_identity()),
-                size: 4096,
-                blocks: 8,
-                atime: Timespec::new(0, 0),
-                mtime: Timespec::new(0, 0),
-                ctime: Timespec::new(0, 0),
-                crtime: Timespec::new(0, 0),
-                kind: FileType::Directory,
-                perm: 0o755,
-                nlink: 2,
-                uid: 0,
-                gid: 0,
-                rdev: 0,
-                flags: 0,
-            },
+                self.forget_child(parent, name);
+                Err(errno)
+            }
+        }
+    }
+
+    pub(crate) fn do_mkdir(&mut self, parent: u64, name: &str, mode: u32) -> Result<fuse::FileAttr, c_int> {
+        self.sync_tree();
+        let dir = self.get_mutable_dir(parent, "mkdir")?;
+        self.check_access(parent, Operation::Write)?;
+        let handler = dir.mkdir(&self.request, name, mode)?;
+        if handler.get_type() != FileType::Directory {
+            error!("mkdir: the handler of ino {parent} made a {:?} instead of a directory", handler.get_type());
+            return Err(EIO);
+        }
+        let ino = self.register_child(parent, name, handler.clone());
+        Ok(Self::get_attr(ino, &handler))
+    }
+
+    pub(crate) fn do_unlink(&mut self, parent: u64, name: &str) -> Result<(), c_int> {
+        self.sync_tree();
+        let dir = self.get_mutable_dir(parent, "unlink")?;
+        self.check_access(parent, Operation::Write)?;
+        dir.unlink(&self.request, name)?;
+        self.forget_child(parent, name);
+        Ok(())
+    }
+
+    pub(crate) fn do_rmdir(&mut self, parent: u64, name: &str) -> Result<(), c_int> {
+        self.sync_tree();
+        let dir = self.get_mutable_dir(parent, "rmdir")?;
+        self.check_access(parent, Operation::Write)?;
+        dir.rmdir(&self.request, name)?;
+        self.forget_child(parent, name);
+        Ok(())
+    }
+
+    /// Forget the item `name` in `parent` and everything below it, after it was removed.
+    fn forget_child(&mut self, parent: u64, name: &str) {
+        let parent_path = self.ino_to_path.get(&parent).cloned().unwrap_or_default();
+        self.forget(&child_path(&parent_path, name), true);
+    }
+
+    /// Rename `name` in `parent` to `new_name` in `new_parent`, which must be the same directory.
+    ///
+    /// The item keeps its inode under the new name.
+    pub(crate) fn do_rename(&mut self, parent: u64, name: &str, new_parent: u64, new_name: &str) -> Result<(), c_int> {
+        self.sync_tree();
+        let dir = self.get_mutable_dir(parent, "rename")?;
+        if new_parent != parent {
+            return Err(EXDEV);
+        }
+        self.check_access(parent, Operation::Write)?;
+        dir.rename(&self.request, name, new_name)?;
+
+        let parent_path = self.ino_to_path.get(&parent).cloned().unwrap_or_default();
+        let (path, new_path) = (child_path(&parent_path, name), child_path(&parent_path, new_name));
+        // Whatever had the new name is gone
+        self.forget(&new_path, true);
+        let ino = match self.path_to_ino.get(&path) {
+            Some(&ino) => ino,
+            None => return Ok(()),
+        };
+
+        // The old name is free for a new item, which shouldn't get this inode
+        if let Some(identity) = self.ino_to_identity.get(&ino) {
+            if self.identity_to_ino.get(identity) == Some(&ino) {
+                self.identity_to_ino.remove(identity);
+            }
+        }
+        // Everything below the item has to be looked up again under its new path
+        self.forget(&path, true);
+
+        if let Some(handler) = dir.lookup_as(&self.request, new_name) {
+            let identity = self.identity_of(parent, &new_path, &handler);
+            self.identity_to_ino.insert(identity, ino);
+            self.ino_to_identity.insert(ino, identity);
+            self.rebuilt_inos.insert(ino);
+            self.ino_to_handler.insert(ino, handler);
+            self.ino_parent.insert(ino, parent);
+            self.ino_to_path.insert(ino, new_path.clone());
+            self.path_to_ino.insert(new_path, ino);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn do_setattr(&mut self, ino: u64, size: Option<u64>) -> Result<fuse::FileAttr, c_int> {
+        self.sync_tree();
+        // Only size changes are supported; other attributes are silently kept as they are
+        if let Some(size) = size {
+            let file = self.get_file(ino, "setattr")?;
+            self.check_access(ino, Operation::Write)?;
+            file.truncate(size)?;
+        }
+
+        self.do_getattr(ino)
+    }
+}
+
+impl<'a> Filesystem for RoutableFilesystem<'a> {
+    #[trace]
+    fn init(&mut self, _req: &fuse::Request) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    fn readdir(&mut self, req: &fuse::Request, ino: u64, _fh: u64, offset: i64, mut reply: fuse::ReplyDirectory) {
+        self.request = RequestContext::from(req);
+        let entries = match self.do_readdir(ino) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
         };
 
-        reply.entry(&Timespec::new(0, 0), &attrs, 0);
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(entry.ino, (i + 1) as i64, entry.kind, OsStr::new(&entry.name)) {
+                break;
+            }
+        }
+
+        reply.ok();
         
     }
 
+    #[trace]
+    fn getattr(&mut self, req: &fuse::Request, ino: u64, reply: fuse::ReplyAttr) {
+        self.request = RequestContext::from(req);
+        match self.do_getattr(ino) {
+            Ok(attr) => reply.attr(&self.ttl_of(ino).attr_timespec(), &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn lookup(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEntry) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_lookup(parent, name),
+            None => Err(ENOENT),
+        };
+        match result {
+            Ok(attr) => reply.entry(&self.ttl_of(attr.ino).entry_timespec(), &attr, self.generation_of(attr.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(&mut self, req: &fuse::Request, ino: u64, fh: u64, offset: i64, size: u32, reply: fuse::ReplyData) {
+        self.request = RequestContext::from(req);
+        self.dispatch_read(ino, fh, offset as u64, size, move |result| match result {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn readlink(&mut self, req: &fuse::Request, ino: u64, reply: fuse::ReplyData) {
+        self.request = RequestContext::from(req);
+        match self.do_readlink(ino) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getxattr(&mut self, req: &fuse::Request, ino: u64, name: &OsStr, size: u32, reply: fuse::ReplyXattr) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_getxattr(ino, name),
+            None => Err(ENODATA),
+        };
+        match result {
+            Ok(value) => Self::reply_xattr(&value, size, reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn listxattr(&mut self, req: &fuse::Request, ino: u64, size: u32, reply: fuse::ReplyXattr) {
+        self.request = RequestContext::from(req);
+        let names = match self.do_listxattr(ino) {
+            Ok(names) => names,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        // The list is every name followed by a NUL byte
+        let mut list = Vec::new();
+        for name in names {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        Self::reply_xattr(&list, size, reply);
+    }
+
+    fn setxattr(&mut self, req: &fuse::Request, ino: u64, name: &OsStr, value: &[u8], _flags: u32, _position: u32, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_setxattr(ino, name, value),
+            None => Err(ENOTSUP),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn removexattr(&mut self, req: &fuse::Request, ino: u64, name: &OsStr, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_removexattr(ino, name),
+            None => Err(ENODATA),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, req: &fuse::Request, ino: u64, flags: u32, reply: fuse::ReplyOpen) {
+        self.request = RequestContext::from(req);
+        match self.do_open(ino, flags) {
+            Ok((fh, open_flags)) => reply.opened(fh, open_flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(&mut self, req: &fuse::Request, ino: u64, fh: u64, offset: i64, data: &[u8], _flags: u32, reply: fuse::ReplyWrite) {
+        self.request = RequestContext::from(req);
+        self.dispatch_write(ino, fh, offset as u64, data, move |result| match result {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn statfs(&mut self, req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
+        self.request = RequestContext::from(req);
+        self.do_statfs().reply(reply);
+    }
+
+    fn flush(&mut self, req: &fuse::Request, ino: u64, fh: u64, _lock_owner: u64, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        match self.do_flush(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(&mut self, req: &fuse::Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        match self.do_release(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn access(&mut self, req: &fuse::Request, ino: u64, mask: u32, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        match self.do_access(ino, mask as i32) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn create(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: fuse::ReplyCreate) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_create(parent, name, mode, flags),
+            None => Err(EINVAL),
+        };
+        match result {
+            Ok((attr, fh, open_flags)) => reply.created(&self.ttl_of(attr.ino).entry_timespec(), &attr, self.generation_of(attr.ino), fh, open_flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mkdir(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, mode: u32, reply: fuse::ReplyEntry) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_mkdir(parent, name, mode),
+            None => Err(EINVAL),
+        };
+        match result {
+            Ok(attr) => reply.entry(&self.ttl_of(attr.ino).entry_timespec(), &attr, self.generation_of(attr.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn unlink(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_unlink(parent, name),
+            None => Err(ENOENT),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rmdir(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        let result = match name.to_str() {
+            Some(name) => self.do_rmdir(parent, name),
+            None => Err(ENOENT),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: fuse::ReplyEmpty) {
+        self.request = RequestContext::from(req);
+        let result = match (name.to_str(), newname.to_str()) {
+            (Some(name), Some(newname)) => self.do_rename(parent, name, newparent, newname),
+            (None, _) => Err(ENOENT),
+            (_, None) => Err(EINVAL),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setattr(&mut self, req: &fuse::Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: fuse::ReplyAttr) {
+        self.request = RequestContext::from(req);
+        match self.do_setattr(ino, size) {
+            Ok(attr) => reply.attr(&self.ttl_of(ino).attr_timespec(), &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+}
+
+impl<'a> Default for RoutableFilesystem<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> RoutableFilesystem<'a> {
@@ -212,30 +1022,222 @@ impl<'a> RoutableFilesystem<'a> {
             path_to_ino,
             ino_to_handler,
             ino_parent: parents,
-            root: DirectoryListing::new(),
+            statfs: None,
+            tree: Arc::new(SharedTree::new(PathHandler::Directory(DirectoryListing::new()))),
+            request: RequestContext::default(),
+            access_policy: None,
             identity_to_ino: std::collections::HashMap::new(),
+            taken_inos: std::collections::HashSet::from([fuse::FUSE_ROOT_ID]),
+            ino_to_identity: std::collections::HashMap::new(),
+            rebuilt_inos: std::collections::HashSet::new(),
             latest_ino: 2,
+            identity_scheme: IdentityScheme::default(),
+            inode_table_path: None,
+            ttl: Ttl::NONE,
+            sessions: std::collections::HashMap::new(),
+            next_fh: 1,
+            opened_versions: std::collections::HashMap::new(),
+            #[cfg(feature = "async")]
+            workers: None,
+            #[cfg(feature = "async")]
+            worker_count: DEFAULT_WORKERS,
         }
     }
 
-    pub fn set_root(&mut self, root: DirectoryListing<'a>) {
-        self.root = root;
-        self.ino_to_handler.insert(1, PathHandler::Directory(self.root.clone()));
+    /// Set how long the kernel may cache attributes and lookups
+    /// of items that don't set their own [`Ttl`].
+    /// 
+    /// The default is [`Ttl::NONE`], so changes made by handlers are seen immediately.
+    pub fn set_ttl(&mut self, ttl: Ttl) {
+        self.ttl = ttl;
+    }
+
+    /// Set how many threads answer reads and writes on async files.
+    ///
+    /// This must be called before the first such request.
+    #[cfg(feature = "async")]
+    pub fn set_async_workers(&mut self, count: usize) {
+        self.worker_count = count;
+    }
+
+    #[cfg(feature = "async")]
+    fn workers(&mut self) -> &WorkerPool {
+        let count = self.worker_count;
+        self.workers.get_or_insert_with(|| WorkerPool::new(count))
+    }
+
+    /// Choose how items without a key are identified.
+    /// 
+    /// This must be called before the filesystem is mounted.
+    pub fn set_identity_scheme(&mut self, scheme: IdentityScheme) {
+        self.identity_scheme = scheme;
+    }
+
+    /// Keep the identity-to-inode table in the given file.
+    /// 
+    /// The table is loaded now if the file exists,
+    /// and saved again when the filesystem is dropped after unmounting.
+    /// Only items with a stable identity (a key, or [`IdentityScheme::Path`]) are saved.
+    ///
+    /// Those already get an inode number derived from their identity without a table.
+    /// The table keeps them stable even if two identities collide,
+    /// since which of them moves to the next free number depends on the order they are seen in.
+    pub fn persist_inodes(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            self.load_inodes(path)?;
+        }
+        self.inode_table_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Load an identity-to-inode table previously written by [`save_inodes`](Self::save_inodes).
+    pub fn load_inodes(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let file = std::fs::File::open(path)?;
+        let table: InodeTable = serde_json::from_reader(std::io::BufReader::new(file))?;
+        for (identity, ino) in table.inodes {
+            self.identity_to_ino.insert(identity, ino);
+            self.taken_inos.insert(ino);
+        }
+        self.latest_ino = self.latest_ino.max(table.latest_ino);
+        Ok(())
+    }
+
+    /// Save the identity-to-inode table for items with a stable identity.
+    pub fn save_inodes(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let table = InodeTable {
+            latest_ino: self.latest_ino,
+            inodes: self.identity_to_ino.iter()
+                .filter(|(identity, _)| identity.is_stable())
+                .map(|(identity, ino)| (*identity, *ino))
+                .collect(),
+        };
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer(file, &table)?;
+        Ok(())
+    }
+
+    /// Set the directory at the top of the filesystem,
+    /// usually a [`DirectoryListing`] or a [`Router`](crate::router::Router).
+    pub fn set_root(&mut self, root: impl Into<PathHandler<'a>>) {
+        let root = root.into();
+        self.identity_to_ino.insert(root.get_identity(), 1);
+        self.ino_to_identity.insert(1, root.get_identity());
+        self.ino_to_handler.insert(1, root.clone());
+        self.tree.lock().root = root;
         self.ino_to_path.insert(1, "/".to_string());
         self.path_to_ino.insert("/".to_string(), 1);
     }
 
 
+    /// Report the numbers from `provider` to `df` and `statvfs(3)`,
+    /// e.g. the capacity of the storage that writes end up on.
+    /// 
+    /// See [`StatFs`] for what is reported otherwise,
+    /// which means rendering every render file in the tree on each `df`.
+    pub fn set_statfs(&mut self, provider: impl Fn() -> StatFs + Send + Sync + 'a) {
+        self.statfs = Some(Box::new(provider));
+    }
+
+    /// Decide which requests may go ahead, by who made them and the path they are for.
+    ///
+    /// Denied requests fail with `EACCES`; see [`AccessPolicy`] for an example.
+    /// To have the kernel check the modes and owners that handlers report instead,
+    /// mount with [`MountOptions::default_permissions`].
+    pub fn set_access_policy(&mut self, policy: impl Fn(&RequestContext, &str, Operation) -> bool + Send + Sync + 'a) {
+        self.access_policy = Some(Box::new(policy));
+    }
+
+    /// A handle for changing the tree while the filesystem is mounted.
+    pub fn tree(&self) -> TreeHandle<'a> {
+        TreeHandle::new(self.tree.clone())
+    }
+
+    /// Whether any file in the tree can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.tree.lock().root.is_writable()
+    }
+
     /// Mount the filesystem at the given path
     /// with sensible defaults.
     /// 
-    /// If you want to customize the mount options,
-    /// use [`fuse::mount`] instead.
-    pub fn mount(self, path: &str) -> () {
-        let options = ["-o", "ro"]
+    /// The filesystem is mounted read-only unless some file in the tree is writable.
+    /// 
+    /// If you want to customize the mount options or handle errors,
+    /// use [`mount_with`](Self::mount_with) instead.
+    /// 
+    /// # Panics
+    /// 
+    /// Panics if the filesystem can't be mounted.
+    pub fn mount(self, path: &str) {
+        self.mount_with(path, &MountOptions::default()).unwrap();
+    }
+
+    /// Mount the filesystem at the given path with the given options.
+    /// 
+    /// This blocks until the filesystem is unmounted.
+    pub fn mount_with(self, path: impl AsRef<Path>, options: &MountOptions) -> Result<(), MountError> {
+        let path = path.as_ref();
+        self.mount_args(path, options, |fs, args| Ok(fuse::mount(fs, &path, args)?))
+    }
+
+    /// Check the mountpoint and hand the filesystem to `mount` along with its mount arguments.
+    fn mount_args<T>(self, path: &Path, options: &MountOptions, mount: impl FnOnce(Self, &[&OsStr]) -> Result<T, MountError>) -> Result<T, MountError> {
+        if !path.is_dir() {
+            return Err(MountError::BadMountpoint(path.to_path_buf()));
+        }
+        let args = options.to_args(self.is_writable())?;
+        let args = args
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-        fuse::mount(self, &path, &options).unwrap();
+        mount(self, &args)
+    }
+}
+
+impl RoutableFilesystem<'static> {
+    /// Mount the filesystem at the given path and serve it from a background thread.
+    /// 
+    /// This returns once the filesystem is mounted.
+    /// The filesystem stays mounted until the returned handle is dropped
+    /// or [`MountHandle::unmount`] is called.
+    pub fn spawn_mount(self, path: impl AsRef<Path>, options: &MountOptions) -> Result<MountHandle, MountError> {
+        let path = path.as_ref();
+        self.mount_args(path, options, |fs, args| {
+            let tree = fs.tree();
+            Ok(crate::mount::spawn_mount(fs, path, args)?.with_tree(tree))
+        })
+    }
+
+    /// Mount the filesystem and serve it until the process gets `SIGINT`, `SIGTERM` or `SIGHUP`,
+    /// then unmount it cleanly.
+    /// 
+    /// See [`signal::mount_until_signal`](crate::signal::mount_until_signal) for details.
+    pub fn mount_until_signal(self, path: impl AsRef<Path>, options: &MountOptions) -> Result<Shutdown, MountError> {
+        let path = path.as_ref();
+        self.mount_args(path, options, |fs, args| crate::signal::mount_until_signal(fs, path, args))
     }
-}
\ No newline at end of file
+}
+
+impl<'a> Drop for RoutableFilesystem<'a> {
+    fn drop(&mut self) {
+        if let Some(path) = &self.inode_table_path {
+            if let Err(err) = self.save_inodes(path) {
+                error!("Failed to save inode table to {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xattr_replies_fit_the_buffer() {
+        assert_eq!(xattr_reply(b"value", 0), Ok(XattrReply::Size(5)));
+        assert_eq!(xattr_reply(b"value", 5), Ok(XattrReply::Data(b"value")));
+        assert_eq!(xattr_reply(b"value", 4), Err(ERANGE));
+    }
+}
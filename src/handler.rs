@@ -1,25 +1,126 @@
-use std::{collections::HashMap, rc::Rc};
+//! The items that make up a filesystem tree, and the traits for implementing them.
+//!
+//! Handlers are shared between threads, so any state that changes must use
+//! interior mutability (e.g. a [`Mutex`] or atomics).
+
+use std::{borrow::Cow, collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
 use fuse::FileType;
+use libc::{c_int, EIO, ENOTSUP, EPERM, EROFS};
 use std::fmt::Debug;
 
+#[cfg(feature = "async")]
+use crate::async_handler::{AsyncFileHandler, Blocking};
+use crate::access::RequestContext;
+use crate::cache::{ChangeNotifier, Ttl};
 use crate::identity::ItemIdentity;
+use crate::metadata::Metadata;
 
 #[derive(Debug, Clone)]
 pub enum PathHandler<'a> {
     File(File<'a>),
     Directory(DirectoryListing<'a>),
+    DynamicDirectory(DynamicDirectory<'a>),
+    Symlink(Symlink<'a>),
 }
 
 impl<'a> PathHandler<'a> {
     pub fn get_type(&self) -> FileType {
         match self {
             PathHandler::File(_) => FileType::RegularFile,
-            PathHandler::Directory(_) => FileType::Directory,
+            PathHandler::Directory(_) | PathHandler::DynamicDirectory(_) => FileType::Directory,
+            PathHandler::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    /// Whether any file at or below this item can be written to.
+    pub fn is_writable(&self) -> bool {
+        match self {
+            PathHandler::File(file) => file.is_writable(),
+            PathHandler::Directory(dir) => dir.is_writable(),
+            PathHandler::DynamicDirectory(dir) => dir.is_writable(),
+            PathHandler::Symlink(_) => false,
+        }
+    }
+
+    /// The metadata of this item, with any overrides applied on top of what the handler reports.
+    pub fn metadata(&self) -> Metadata {
+        match self {
+            PathHandler::File(file) => file.metadata(),
+            PathHandler::Directory(dir) => dir.metadata(),
+            PathHandler::DynamicDirectory(dir) => dir.metadata(),
+            PathHandler::Symlink(link) => link.metadata(),
+        }
+    }
+
+    /// Names of the extended attributes of this item.
+    pub fn list_xattrs(&self) -> Vec<String> {
+        match self {
+            PathHandler::File(file) => file.list_xattrs(),
+            PathHandler::Directory(dir) => dir.list_xattrs(),
+            PathHandler::DynamicDirectory(_) | PathHandler::Symlink(_) => Vec::new(),
+        }
+    }
+
+    /// The value of the extended attribute `name`, if this item has it.
+    pub fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        match self {
+            PathHandler::File(file) => file.get_xattr(name),
+            PathHandler::Directory(dir) => dir.get_xattr(name),
+            PathHandler::DynamicDirectory(_) | PathHandler::Symlink(_) => None,
+        }
+    }
+
+    /// How long the kernel may cache this item, if it says so itself.
+    ///
+    /// Without an explicit [`Ttl`], immutable files get [`Ttl::IMMUTABLE`]
+    /// and everything else uses the filesystem-wide setting.
+    pub fn ttl(&self) -> Option<Ttl> {
+        match self {
+            PathHandler::File(file) => file.ttl(),
+            PathHandler::Directory(dir) => dir.ttl,
+            PathHandler::DynamicDirectory(dir) => dir.ttl,
+            PathHandler::Symlink(link) => link.ttl,
+        }
+    }
+
+    /// Override the metadata of this item.
+    pub fn with_metadata(self, metadata: Metadata) -> Self {
+        match self {
+            PathHandler::File(file) => PathHandler::File(file.with_metadata(metadata)),
+            PathHandler::Directory(dir) => PathHandler::Directory(dir.with_metadata(metadata)),
+            PathHandler::DynamicDirectory(dir) => PathHandler::DynamicDirectory(dir.with_metadata(metadata)),
+            PathHandler::Symlink(link) => PathHandler::Symlink(link.with_metadata(metadata)),
         }
     }
 }
 
+impl<'a> From<File<'a>> for PathHandler<'a> {
+    fn from(file: File<'a>) -> Self {
+        PathHandler::File(file)
+    }
+}
+
+impl<'a> From<DirectoryListing<'a>> for PathHandler<'a> {
+    fn from(dir: DirectoryListing<'a>) -> Self {
+        PathHandler::Directory(dir)
+    }
+}
+
+impl<'a> From<DynamicDirectory<'a>> for PathHandler<'a> {
+    fn from(dir: DynamicDirectory<'a>) -> Self {
+        PathHandler::DynamicDirectory(dir)
+    }
+}
+
+impl<'a> From<Symlink<'a>> for PathHandler<'a> {
+    fn from(link: Symlink<'a>) -> Self {
+        PathHandler::Symlink(link)
+    }
+}
+
 pub(crate) trait Identifiable {
     fn get_identity(&self) -> ItemIdentity;
 }
@@ -29,6 +130,8 @@ impl<'a> Identifiable for PathHandler<'a> {
         match self {
             PathHandler::File(handler) => handler.get_identity(),
             PathHandler::Directory(handler) => handler.get_identity(),
+            PathHandler::DynamicDirectory(handler) => handler.get_identity(),
+            PathHandler::Symlink(handler) => handler.get_identity(),
         }
     }
 }
@@ -36,19 +139,232 @@ impl<'a> Identifiable for PathHandler<'a> {
 #[derive(Debug, Clone)]
 pub struct File<'a> {
     identity: ItemIdentity,
-    implementation: Rc<Box<dyn FileHandler + 'a>>,
+    metadata: Metadata,
+    xattrs: BTreeMap<String, Vec<u8>>,
+    ttl: Option<Ttl>,
+    immutable: bool,
+    notifier: Option<ChangeNotifier>,
+    implementation: Arc<dyn FileHandler + 'a>,
+    /// The handler behind `implementation`, if it is async,
+    /// so reads and writes can be sent to a worker thread instead of blocking on them.
+    #[cfg(feature = "async")]
+    asynchronous: Option<Arc<dyn AsyncFileHandler>>,
 }
 impl<'a> File<'a> {
     pub fn from_impl(implementation: impl FileHandler + 'a) -> File<'a> {
+        File::from_shared(Arc::new(implementation))
+    }
+
+    /// Use a handler that the application keeps its own reference to,
+    /// for example to update its state from another thread while the filesystem is mounted.
+    pub fn from_shared(implementation: Arc<dyn FileHandler + 'a>) -> File<'a> {
         File {
             identity: ItemIdentity::new(),
-            implementation: Rc::new(Box::new(implementation)),
+            metadata: Metadata::new(),
+            xattrs: BTreeMap::new(),
+            ttl: None,
+            immutable: false,
+            notifier: None,
+            implementation,
+            #[cfg(feature = "async")]
+            asynchronous: None,
+        }
+    }
+
+    /// A file whose size and contents come from closures,
+    /// for when a whole [`FileHandler`] impl would be overkill.
+    /// 
+    /// `read` gets the same arguments as [`FileHandler::read`].
+    pub fn from_fn(
+        size: impl Fn() -> u64 + Send + Sync + 'a,
+        read: impl Fn(u64, u32) -> Result<Vec<u8>, c_int> + Send + Sync + 'a,
+    ) -> File<'a> {
+        File::from_impl(FnFile { size: Box::new(size), read: Box::new(read) })
+    }
+
+    /// A file whose whole content is produced by `render` every time it is opened,
+    /// like the files in `/proc`.
+    /// 
+    /// Every open reads the content as it was rendered for that open,
+    /// while `stat` reports the size of the latest render.
+    pub fn from_render(render: impl Fn() -> Vec<u8> + Send + Sync + 'a) -> File<'a> {
+        File::from_impl(RenderFile { render: Box::new(render), content: Mutex::new(None) })
+    }
+
+    /// Use a handler whose reads and writes are async.
+    ///
+    /// When mounted, these are answered from a pool of worker threads,
+    /// so a slow read does not hold up other requests.
+    #[cfg(feature = "async")]
+    pub fn from_async(implementation: impl AsyncFileHandler + 'static) -> File<'a> {
+        let implementation: Arc<dyn AsyncFileHandler> = Arc::new(implementation);
+        File {
+            asynchronous: Some(implementation.clone()),
+            ..File::from_impl(Blocking(implementation))
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn async_handler(&self) -> Option<Arc<dyn AsyncFileHandler>> {
+        self.asynchronous.clone()
+    }
+
+    /// Override the metadata reported by the handler.
+    /// 
+    /// Fields left as `None` still come from [`FileHandler::metadata`].
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// The metadata of this file, with the time of the latest reported change
+    /// as its mtime and ctime if that is later than what the handler says.
+    pub fn metadata(&self) -> Metadata {
+        let mut metadata = self.metadata.clone().or(self.implementation.metadata());
+        if let Some(changed) = self.change_notifier().and_then(|notifier| notifier.changed_at()) {
+            metadata.mtime = Some(metadata.mtime.map_or(changed, |mtime| mtime.max(changed)));
+            metadata.ctime = Some(metadata.ctime.map_or(changed, |ctime| ctime.max(changed)));
+        }
+        metadata
+    }
+
+    /// Attach a fixed extended attribute, e.g. `user.source`.
+    /// 
+    /// These take precedence over attributes of the same name from the handler.
+    pub fn with_xattr(mut self, name: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.xattrs.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn list_xattrs(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.xattrs.keys().cloned().collect();
+        for name in self.implementation.list_xattrs() {
+            if !self.xattrs.contains_key(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    pub fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        match self.xattrs.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.implementation.get_xattr(name),
+        }
+    }
+
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> Result<(), c_int> {
+        self.implementation.set_xattr(name, value)
+    }
+
+    pub fn remove_xattr(&self, name: &str) -> Result<(), c_int> {
+        self.implementation.remove_xattr(name)
+    }
+
+    /// Identify this file by a key instead of a random value,
+    /// so it keeps the same inode number across mounts.
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.identity = ItemIdentity::from_key(key);
+        self
+    }
+
+    /// Set how long the kernel may cache this file's attributes and lookups.
+    pub fn with_ttl(mut self, ttl: Ttl) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Declare that this file's size and contents never change,
+    /// whatever [`FileHandler::is_immutable`] says.
+    pub fn immutable(mut self) -> Self {
+        self.immutable = true;
+        self
+    }
+
+    /// Use `notifier` to report changes to this file,
+    /// in place of [`FileHandler::change_notifier`].
+    pub fn with_notifier(mut self, notifier: ChangeNotifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn change_notifier(&self) -> Option<ChangeNotifier> {
+        self.notifier.clone().or_else(|| self.implementation.change_notifier())
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.immutable || self.implementation.is_immutable()
+    }
+
+    pub fn ttl(&self) -> Option<Ttl> {
+        match self.ttl {
+            Some(ttl) => Some(ttl),
+            None if self.is_immutable() => Some(Ttl::IMMUTABLE),
+            None => None,
         }
     }
 
+    /// Let the handler know the file is being opened with the given `open(2)` flags,
+    /// returning the session for that open if the handler keeps one.
+    pub fn open(&self, flags: u32) -> Result<Option<Box<dyn FileSession>>, c_int> {
+        self.implementation.open(flags)
+    }
+
+    /// Like [`open`](File::open), on behalf of the process described by `context`.
+    pub fn open_as(&self, context: &RequestContext, flags: u32) -> Result<Option<Box<dyn FileSession>>, c_int> {
+        self.implementation.open_as(context, flags)
+    }
+
+    pub fn is_direct_io(&self) -> bool {
+        self.implementation.is_direct_io()
+    }
+
     pub fn get_size(&self) -> u64 {
         self.implementation.get_size()
     }
+
+    /// Read up to `size` bytes starting at `offset`.
+    ///
+    /// The request is clamped at the end of the file before it reaches the handler,
+    /// so reading at or past the end returns an empty buffer.
+    pub fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        match self.clamp_read(offset, size) {
+            Some(size) => self.implementation.read(offset, size),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Like [`read`](File::read), on behalf of the process described by `context`.
+    pub fn read_as(&self, context: &RequestContext, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        match self.clamp_read(offset, size) {
+            Some(size) => self.implementation.read_as(context, offset, size),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The number of bytes a read of `size` at `offset` should ask the handler for,
+    /// or `None` if it starts at or past the end of the file.
+    pub(crate) fn clamp_read(&self, offset: u64, size: u32) -> Option<u32> {
+        let file_size = self.get_size();
+        if offset >= file_size {
+            return None;
+        }
+        Some((size as u64).min(file_size - offset) as u32)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.implementation.is_writable()
+    }
+
+    /// Write `data` starting at `offset`, returning the number of bytes written.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        self.implementation.write(offset, data)
+    }
+
+    /// Change the size of the file to `size` bytes.
+    pub fn truncate(&self, size: u64) -> Result<(), c_int> {
+        self.implementation.truncate(size)
+    }
 }
 
 impl<'a> Identifiable for File<'a> {
@@ -61,24 +377,176 @@ impl<'a> Identifiable for File<'a> {
 #[derive(Debug, Clone)]
 pub struct DirectoryListing<'a> {
     identity: ItemIdentity,
+    metadata: Metadata,
+    xattrs: BTreeMap<String, Vec<u8>>,
+    ttl: Option<Ttl>,
     items: HashMap<String, PathHandler<'a>>,
 }
+impl<'a> Default for DirectoryListing<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> DirectoryListing<'a> {
     pub fn new() -> DirectoryListing<'a> {
         DirectoryListing {
             items: HashMap::new(),
             identity: ItemIdentity::new(),
+            metadata: Metadata::new(),
+            xattrs: BTreeMap::new(),
+            ttl: None,
+        }
+    }
+
+    /// Set the metadata of this directory itself.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata.clone()
+    }
+
+    /// Set how long the kernel may cache this directory's attributes and lookups.
+    pub fn with_ttl(mut self, ttl: Ttl) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Attach an extended attribute to this directory itself.
+    pub fn with_xattr(mut self, name: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.xattrs.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn list_xattrs(&self) -> Vec<String> {
+        self.xattrs.keys().cloned().collect()
+    }
+
+    pub fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        self.xattrs.get(name).cloned()
+    }
+
+    /// Override the metadata of the item called `name`,
+    /// which must already have been added.
+    /// 
+    /// Does nothing if there is no such item.
+    pub fn with_entry_metadata(mut self, name: &str, metadata: Metadata) -> Self {
+        if let Some(item) = self.items.remove(name) {
+            self.items.insert(name.to_string(), item.with_metadata(metadata));
         }
+        self
     }
 
-    pub fn listdir(&self) -> Vec<(String, PathHandler)> {
+    /// Identify this directory by a key instead of a random value,
+    /// so it keeps the same inode number across mounts.
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.identity = ItemIdentity::from_key(key);
+        self
+    }
+
+    pub fn listdir(&self) -> Vec<(String, PathHandler<'a>)> {
         self.items.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
     }
 
+    /// Find a single item in this directory by name.
+    pub fn get(&self, name: &str) -> Option<PathHandler<'a>> {
+        self.items.get(name).cloned()
+    }
+
+    pub(crate) fn entry(&self, name: &str) -> Option<&PathHandler<'a>> {
+        self.items.get(name)
+    }
+
+    pub(crate) fn entry_mut(&mut self, name: &str) -> Option<&mut PathHandler<'a>> {
+        self.items.get_mut(name)
+    }
+
+    /// Put an item in this directory, returning the one it replaced.
+    pub(crate) fn insert(&mut self, name: &str, item: PathHandler<'a>) -> Option<PathHandler<'a>> {
+        self.items.insert(name.to_string(), item)
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> Option<PathHandler<'a>> {
+        self.items.remove(name)
+    }
+
+    /// Add an already-built item, such as a [`File`] with a key.
+    pub fn add(mut self, name: &str, item: impl Into<PathHandler<'a>>) -> Self {
+        self.items.insert(name.to_string(), item.into());
+        self
+    }
+
     pub fn add_file(mut self, name: &str, file: impl FileHandler + 'a) -> Self {
         self.items.insert(name.to_string(), PathHandler::File(File::from_impl(file)));
         self
     }
+
+    /// Add a file whose size and contents come from closures; see [`File::from_fn`].
+    pub fn add_fn_file(
+        self,
+        name: &str,
+        size: impl Fn() -> u64 + Send + Sync + 'a,
+        read: impl Fn(u64, u32) -> Result<Vec<u8>, c_int> + Send + Sync + 'a,
+    ) -> Self {
+        self.add(name, File::from_fn(size, read))
+    }
+
+    /// Add a file whose content is rendered on every open; see [`File::from_render`].
+    /// 
+    /// ```
+    /// # use fusible::handler::DirectoryListing;
+    /// let root = DirectoryListing::new()
+    ///     .add_render_file("pid", || format!("{}\n", std::process::id()).into_bytes());
+    /// ```
+    pub fn add_render_file(self, name: &str, render: impl Fn() -> Vec<u8> + Send + Sync + 'a) -> Self {
+        self.add(name, File::from_render(render))
+    }
+
+    /// Add a file with fixed content, like a `README`.
+    pub fn add_static_file(self, name: &str, content: impl Into<StaticFile>) -> Self {
+        self.add_file(name, content.into())
+    }
+
+    /// Add a subdirectory, which may itself contain further subdirectories.
+    pub fn add_dir(mut self, name: &str, dir: DirectoryListing<'a>) -> Self {
+        self.items.insert(name.to_string(), PathHandler::Directory(dir));
+        self
+    }
+
+    /// Add a subdirectory whose contents are computed on every request.
+    pub fn add_dynamic_dir(mut self, name: &str, dir: impl DirectoryHandler<'a> + 'a) -> Self {
+        self.items.insert(name.to_string(), PathHandler::DynamicDirectory(DynamicDirectory::from_impl(dir)));
+        self
+    }
+
+    /// Add a directory that items can be created in and removed from.
+    pub fn add_mutable_dir(mut self, name: &str, dir: impl MutableDirectory<'a> + 'a) -> Self {
+        self.items.insert(name.to_string(), PathHandler::DynamicDirectory(DynamicDirectory::from_mutable(dir)));
+        self
+    }
+
+    /// Add a symbolic link pointing at `target`.
+    /// 
+    /// The target is not checked: it may be relative, absolute, or point nowhere.
+    pub fn add_symlink(mut self, name: &str, target: &str) -> Self {
+        self.items.insert(name.to_string(), PathHandler::Symlink(Symlink::new(target)));
+        self
+    }
+
+    /// Add a symbolic link whose target is computed every time it is read,
+    /// like a `current` link pointing at the latest log file.
+    pub fn add_computed_symlink(mut self, name: &str, target: impl Fn() -> String + Send + Sync + 'a) -> Self {
+        self.items.insert(name.to_string(), PathHandler::Symlink(Symlink::computed(target)));
+        self
+    }
+
+    /// Whether any file in this directory (or below it) can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.items.values().any(|item| item.is_writable())
+    }
 }
 
 impl<'a> Identifiable for DirectoryListing<'a> {
@@ -87,6 +555,655 @@ impl<'a> Identifiable for DirectoryListing<'a> {
     }
 }
 
-pub trait FileHandler: std::fmt::Debug {
+#[derive(Clone)]
+enum SymlinkTarget<'a> {
+    Static(String),
+    Computed(Arc<dyn Fn() -> String + Send + Sync + 'a>),
+}
+
+impl<'a> Debug for SymlinkTarget<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymlinkTarget::Static(target) => f.debug_tuple("Static").field(target).finish(),
+            SymlinkTarget::Computed(_) => f.debug_tuple("Computed").finish(),
+        }
+    }
+}
+
+/// A symbolic link, pointing at a fixed or computed target.
+#[derive(Debug, Clone)]
+pub struct Symlink<'a> {
+    identity: ItemIdentity,
+    metadata: Metadata,
+    ttl: Option<Ttl>,
+    target: SymlinkTarget<'a>,
+}
+impl<'a> Symlink<'a> {
+    pub fn new(target: &str) -> Symlink<'a> {
+        Symlink {
+            identity: ItemIdentity::new(),
+            metadata: Metadata::new(),
+            ttl: None,
+            target: SymlinkTarget::Static(target.to_string()),
+        }
+    }
+
+    /// A symbolic link whose target is computed every time it is read.
+    pub fn computed(target: impl Fn() -> String + Send + Sync + 'a) -> Symlink<'a> {
+        Symlink {
+            identity: ItemIdentity::new(),
+            metadata: Metadata::new(),
+            ttl: None,
+            target: SymlinkTarget::Computed(Arc::new(target)),
+        }
+    }
+
+    /// Identify this link by a key instead of a random value,
+    /// so it keeps the same inode number across mounts.
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.identity = ItemIdentity::from_key(key);
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata.clone()
+    }
+
+    /// Set how long the kernel may cache this link's attributes and lookups.
+    ///
+    /// A computed target is still read on every `readlink`.
+    pub fn with_ttl(mut self, ttl: Ttl) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn target(&self) -> String {
+        match &self.target {
+            SymlinkTarget::Static(target) => target.clone(),
+            SymlinkTarget::Computed(target) => target(),
+        }
+    }
+}
+
+impl<'a> Identifiable for Symlink<'a> {
+    fn get_identity(&self) -> ItemIdentity {
+        self.identity
+    }
+}
+
+/// A directory whose contents are computed when they are asked for.
+#[derive(Debug, Clone)]
+pub struct DynamicDirectory<'a> {
+    identity: ItemIdentity,
+    metadata: Metadata,
+    ttl: Option<Ttl>,
+    implementation: Arc<dyn DirectoryHandler<'a> + 'a>,
+    /// The handler behind `implementation`, if items can be created and removed in it.
+    mutable: Option<Arc<dyn MutableDirectory<'a> + 'a>>,
+}
+impl<'a> DynamicDirectory<'a> {
+    pub fn from_impl(implementation: impl DirectoryHandler<'a> + 'a) -> DynamicDirectory<'a> {
+        DynamicDirectory::from_shared(Arc::new(implementation))
+    }
+
+    /// Use a handler that the application keeps its own reference to,
+    /// for example to update its state from another thread while the filesystem is mounted.
+    pub fn from_shared(implementation: Arc<dyn DirectoryHandler<'a> + 'a>) -> DynamicDirectory<'a> {
+        DynamicDirectory {
+            identity: ItemIdentity::new(),
+            metadata: Metadata::new(),
+            ttl: None,
+            implementation,
+            mutable: None,
+        }
+    }
+
+    /// Use a handler that items can be created in and removed from.
+    pub fn from_mutable(implementation: impl MutableDirectory<'a> + 'a) -> DynamicDirectory<'a> {
+        let implementation: Arc<dyn MutableDirectory<'a> + 'a> = Arc::new(implementation);
+        DynamicDirectory {
+            mutable: Some(implementation.clone()),
+            ..DynamicDirectory::from_shared(implementation)
+        }
+    }
+
+    pub(crate) fn mutable(&self) -> Option<Arc<dyn MutableDirectory<'a> + 'a>> {
+        self.mutable.clone()
+    }
+
+    /// Set how long the kernel may cache this directory's attributes and lookups.
+    ///
+    /// This does not apply to the items inside it, which can set their own.
+    pub fn with_ttl(mut self, ttl: Ttl) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Override the metadata reported by the handler.
+    /// 
+    /// Fields left as `None` still come from [`DirectoryHandler::metadata`].
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata.clone().or(self.implementation.metadata())
+    }
+
+    /// Identify this directory by a key instead of a random value,
+    /// so it keeps the same inode number across mounts.
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.identity = ItemIdentity::from_key(key);
+        self
+    }
+
+    pub fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+        self.implementation.list()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<PathHandler<'a>> {
+        self.implementation.lookup(name)
+    }
+
+    pub fn list_as(&self, context: &RequestContext) -> Vec<(String, PathHandler<'a>)> {
+        self.implementation.list_as(context)
+    }
+
+    pub fn lookup_as(&self, context: &RequestContext, name: &str) -> Option<PathHandler<'a>> {
+        self.implementation.lookup_as(context, name)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.mutable.is_some() || self.implementation.is_writable()
+    }
+}
+
+impl<'a> Identifiable for DynamicDirectory<'a> {
+    fn get_identity(&self) -> ItemIdentity {
+        self.identity
+    }
+}
+
+/// The contents of a directory that can change between requests, like `/proc/<pid>`.
+///
+/// Items returned from here, and everything below them, get their inode number from their path
+/// under the directory, so it is fine to build fresh [`PathHandler`]s on every call,
+/// including directories nested inside each other.
+pub trait DirectoryHandler<'a>: std::fmt::Debug + Send + Sync {
+    /// List every item currently in the directory.
+    fn list(&self) -> Vec<(String, PathHandler<'a>)>;
+
+    /// Find a single item by name.
+    ///
+    /// The default implementation searches [`list`](DirectoryHandler::list);
+    /// override it if building the full listing is expensive.
+    fn lookup(&self, name: &str) -> Option<PathHandler<'a>> {
+        self.list().into_iter().find(|(n, _)| n == name).map(|(_, handler)| handler)
+    }
+
+    /// List the directory as the process described by `context` should see it.
+    ///
+    /// The filesystem always calls this instead of [`list`](DirectoryHandler::list),
+    /// which it calls by default.
+    fn list_as(&self, _context: &RequestContext) -> Vec<(String, PathHandler<'a>)> {
+        self.list()
+    }
+
+    /// Find a single item by name, as the process described by `context` should see it.
+    ///
+    /// This calls [`lookup`](DirectoryHandler::lookup) by default,
+    /// so handlers that override [`list_as`](DirectoryHandler::list_as) should override this too.
+    fn lookup_as(&self, _context: &RequestContext, name: &str) -> Option<PathHandler<'a>> {
+        self.lookup(name)
+    }
+
+    /// Whether files in this directory may accept writes.
+    ///
+    /// The contents are not known until the filesystem is running,
+    /// so this is what decides whether the filesystem is mounted read-write.
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Permissions, owner and timestamps of the directory itself.
+    ///
+    /// Anything left as `None` gets the filesystem's default.
+    fn metadata(&self) -> Metadata {
+        Metadata::default()
+    }
+}
+
+/// A [`DirectoryHandler`] that items can be created in, removed from and renamed in,
+/// like a drop box where every new file starts a job.
+///
+/// Add one with [`DirectoryListing::add_mutable_dir`] or [`DynamicDirectory::from_mutable`].
+/// Every method fails with `EPERM` by default, so handlers only implement what they support;
+/// directories that aren't mutable at all fail with `EROFS` instead.
+///
+/// After a change, [`lookup_as`](DirectoryHandler::lookup_as) and [`list_as`](DirectoryHandler::list_as)
+/// should reflect it, since that is how the filesystem finds the items again.
+pub trait MutableDirectory<'a>: DirectoryHandler<'a> {
+    /// Create the file `name` for `creat(2)` or `open(2)` with `O_CREAT`, returning it.
+    ///
+    /// `mode` holds the requested permission bits. The file is opened right after,
+    /// so it should be writable if the caller is going to write to it.
+    fn create(&self, _context: &RequestContext, _name: &str, _mode: u32) -> Result<File<'a>, c_int> {
+        Err(EPERM)
+    }
+
+    /// Create the subdirectory `name`, returning it.
+    fn mkdir(&self, _context: &RequestContext, _name: &str, _mode: u32) -> Result<PathHandler<'a>, c_int> {
+        Err(EPERM)
+    }
+
+    /// Remove the file `name`.
+    fn unlink(&self, _context: &RequestContext, _name: &str) -> Result<(), c_int> {
+        Err(EPERM)
+    }
+
+    /// Remove the subdirectory `name`, which should fail with [`libc::ENOTEMPTY`] if it isn't empty.
+    fn rmdir(&self, _context: &RequestContext, _name: &str) -> Result<(), c_int> {
+        Err(EPERM)
+    }
+
+    /// Rename `name` to `new_name`, replacing whatever was at `new_name`.
+    ///
+    /// Renames between different directories fail with `EXDEV` before they get here,
+    /// which makes `mv` fall back to copying.
+    fn rename(&self, _context: &RequestContext, _name: &str, _new_name: &str) -> Result<(), c_int> {
+        Err(EPERM)
+    }
+}
+
+/// The contents of a single file.
+pub trait FileHandler: std::fmt::Debug + Send + Sync {
     fn get_size(&self) -> u64;
-}
\ No newline at end of file
+
+    /// Read `size` bytes starting at `offset`.
+    ///
+    /// The filesystem never asks for bytes past [`get_size`](FileHandler::get_size),
+    /// so `offset + size` is always within the file.
+    /// On failure, return an errno value (e.g. [`libc::EIO`]).
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int>;
+
+    /// Read on behalf of the process described by `context`, e.g. to show each user their own content.
+    ///
+    /// The filesystem always calls this instead of [`read`](FileHandler::read), which it calls by default.
+    /// The kernel caches pages regardless of who read them, so files whose content depends on
+    /// the caller should also return `true` from [`is_direct_io`](FileHandler::is_direct_io).
+    fn read_as(&self, _context: &RequestContext, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.read(offset, size)
+    }
+
+    /// Whether this file accepts writes.
+    ///
+    /// If no file in the tree is writable, the filesystem is mounted read-only.
+    /// Handlers that override [`write`](FileHandler::write) should return `true` here.
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Write `data` starting at `offset`, returning the number of bytes written.
+    ///
+    /// Writes may go past [`get_size`](FileHandler::get_size);
+    /// it is up to the handler whether that grows the file or fails (e.g. with [`libc::ENOSPC`]).
+    fn write(&self, _offset: u64, _data: &[u8]) -> Result<u32, c_int> {
+        Err(EROFS)
+    }
+
+    /// Change the size of the file, as requested by `truncate(2)` or `open(2)` with `O_TRUNC`.
+    fn truncate(&self, _size: u64) -> Result<(), c_int> {
+        Err(EROFS)
+    }
+
+    /// Called when the file is opened, before any reads or writes through that open.
+    /// 
+    /// `flags` are the `open(2)` flags, e.g. [`libc::O_RDONLY`].
+    /// Returning a [`FileSession`] sends every read, write and flush on this open to it
+    /// instead of to the handler, until the file is closed.
+    /// Returning an error fails the `open(2)` call with it.
+    fn open(&self, _flags: u32) -> Result<Option<Box<dyn FileSession>>, c_int> {
+        Ok(None)
+    }
+
+    /// Like [`open`](FileHandler::open), on behalf of the process described by `context`.
+    ///
+    /// The filesystem always calls this instead of [`open`](FileHandler::open), which it calls by default.
+    fn open_as(&self, _context: &RequestContext, flags: u32) -> Result<Option<Box<dyn FileSession>>, c_int> {
+        self.open(flags)
+    }
+
+    /// Whether reads should bypass the kernel's page cache.
+    /// 
+    /// This is needed when the size reported before opening may not match the content,
+    /// since the kernel otherwise stops reading at the size it last saw.
+    fn is_direct_io(&self) -> bool {
+        false
+    }
+
+    /// The handle this file reports its changes through, if it has one.
+    /// 
+    /// Files with a notifier keep the kernel's page cache between opens until they report a change;
+    /// see [`ChangeNotifier`] for what happens then.
+    fn change_notifier(&self) -> Option<ChangeNotifier> {
+        None
+    }
+
+    /// Whether the size and contents of this file never change.
+    ///
+    /// The kernel then caches the attributes for [`Ttl::IMMUTABLE`]
+    /// and keeps the page cache between opens, so most reads never reach the handler.
+    fn is_immutable(&self) -> bool {
+        false
+    }
+
+    /// Permissions, owner and timestamps of the file.
+    ///
+    /// Anything left as `None` gets the filesystem's default.
+    fn metadata(&self) -> Metadata {
+        Metadata::default()
+    }
+
+    /// Names of the extended attributes this file has, like `user.fusible.range`.
+    fn list_xattrs(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The value of the extended attribute `name`, or `None` if the file does not have it.
+    fn get_xattr(&self, _name: &str) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Set the extended attribute `name`, as requested by `setxattr(2)`.
+    fn set_xattr(&self, _name: &str, _value: &[u8]) -> Result<(), c_int> {
+        Err(ENOTSUP)
+    }
+
+    /// Remove the extended attribute `name`, as requested by `removexattr(2)`.
+    fn remove_xattr(&self, _name: &str) -> Result<(), c_int> {
+        Err(ENOTSUP)
+    }
+}
+
+type SizeFn<'a> = Box<dyn Fn() -> u64 + Send + Sync + 'a>;
+type ReadFn<'a> = Box<dyn Fn(u64, u32) -> Result<Vec<u8>, c_int> + Send + Sync + 'a>;
+
+/// The state of a single open of a file, as returned by [`FileHandler::open`].
+///
+/// This is what lets two processes reading the same file each see a consistent version of it.
+/// Sessions are owned by the filesystem and only used by one request at a time,
+/// so unlike handlers they can change their state through `&mut self`.
+/// The session is dropped when the file is closed, after [`release`](FileSession::release).
+pub trait FileSession: std::fmt::Debug + Send {
+    /// Read up to `size` bytes starting at `offset`.
+    ///
+    /// Unlike [`FileHandler::read`], requests are not clamped to the size of the file,
+    /// since the session may have content of a different size;
+    /// return fewer bytes, or none, at the end.
+    fn read(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, c_int>;
+
+    /// Write `data` starting at `offset`, returning the number of bytes written.
+    fn write(&mut self, _offset: u64, _data: &[u8]) -> Result<u32, c_int> {
+        Err(EROFS)
+    }
+
+    /// Called on every `close(2)` of a file descriptor for this open, which may happen more than once after `dup(2)`.
+    ///
+    /// An error here is returned from `close(2)`.
+    fn flush(&mut self) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    /// Called once the last file descriptor for this open is closed.
+    fn release(&mut self) -> Result<(), c_int> {
+        Ok(())
+    }
+}
+
+/// A session that serves content captured when the file was opened.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    content: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn new(content: Vec<u8>) -> Snapshot {
+        Snapshot { content }
+    }
+}
+
+impl FileSession for Snapshot {
+    fn read(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        Ok(clamped(&self.content, offset, size).to_vec())
+    }
+}
+
+/// The part of `content` that a read of `size` bytes at `offset` covers,
+/// which may be shorter than asked (or empty) if the content changed since the read was clamped.
+pub(crate) fn clamped(content: &[u8], offset: u64, size: u32) -> &[u8] {
+    let start = (offset as usize).min(content.len());
+    let end = start.saturating_add(size as usize).min(content.len());
+    &content[start..end]
+}
+
+/// The handler behind [`File::from_fn`].
+struct FnFile<'a> {
+    size: SizeFn<'a>,
+    read: ReadFn<'a>,
+}
+
+impl<'a> Debug for FnFile<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FnFile").finish_non_exhaustive()
+    }
+}
+
+impl<'a> FileHandler for FnFile<'a> {
+    fn get_size(&self) -> u64 {
+        (self.size)()
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        (self.read)(offset, size)
+    }
+}
+
+/// The handler behind [`File::from_render`].
+struct RenderFile<'a> {
+    render: Box<dyn Fn() -> Vec<u8> + Send + Sync + 'a>,
+    /// The content as of the latest open, or `None` if it was never opened.
+    /// This is only read directly when a read comes without a session.
+    content: Mutex<Option<Vec<u8>>>,
+}
+
+impl<'a> RenderFile<'a> {
+    fn with_content<T>(&self, f: impl FnOnce(&[u8]) -> T) -> T {
+        let mut content = self.content.lock().unwrap();
+        // `stat` before the first open should still report a sensible size
+        let content = content.get_or_insert_with(|| (self.render)());
+        f(content)
+    }
+}
+
+impl<'a> Debug for RenderFile<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RenderFile").field("content", &self.content).finish_non_exhaustive()
+    }
+}
+
+impl<'a> FileHandler for RenderFile<'a> {
+    fn get_size(&self) -> u64 {
+        self.with_content(|content| content.len() as u64)
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        Ok(self.with_content(|content| clamped(content, offset, size).to_vec()))
+    }
+
+    fn open(&self, _flags: u32) -> Result<Option<Box<dyn FileSession>>, c_int> {
+        let content = (self.render)();
+        *self.content.lock().unwrap() = Some(content.clone());
+        Ok(Some(Box::new(Snapshot::new(content))))
+    }
+
+    fn is_direct_io(&self) -> bool {
+        true
+    }
+}
+
+/// A file whose content is fixed when it is created.
+///
+/// ```
+/// # use fusible::handler::{DirectoryListing, StaticFile};
+/// let root = DirectoryListing::new()
+///     .add_static_file("README", "Nothing to see here\n")
+///     .add_file("logo.png", StaticFile::new(vec![0x89, b'P', b'N', b'G']));
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticFile {
+    content: Cow<'static, [u8]>,
+}
+
+impl StaticFile {
+    pub fn new(content: impl Into<Cow<'static, [u8]>>) -> StaticFile {
+        StaticFile { content: content.into() }
+    }
+}
+
+impl From<Vec<u8>> for StaticFile {
+    fn from(content: Vec<u8>) -> Self {
+        StaticFile::new(content)
+    }
+}
+
+impl From<&'static [u8]> for StaticFile {
+    fn from(content: &'static [u8]) -> Self {
+        StaticFile::new(content)
+    }
+}
+
+impl From<String> for StaticFile {
+    fn from(content: String) -> Self {
+        StaticFile::new(content.into_bytes())
+    }
+}
+
+impl From<&'static str> for StaticFile {
+    fn from(content: &'static str) -> Self {
+        StaticFile::new(content.as_bytes())
+    }
+}
+
+impl FileHandler for StaticFile {
+    fn get_size(&self) -> u64 {
+        self.content.len() as u64
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        Ok(clamped(&self.content, offset, size).to_vec())
+    }
+
+    fn is_immutable(&self) -> bool {
+        true
+    }
+}
+
+/// A file that passes reads, and optionally writes, through to a file on the host.
+///
+/// The size, permissions, owner and timestamps are those of the host file,
+/// so changes made to it from outside show up as well.
+#[derive(Debug)]
+pub struct HostFile {
+    path: PathBuf,
+    file: std::fs::File,
+    writable: bool,
+}
+
+impl HostFile {
+    /// Open the file at `path` for reading.
+    pub fn new(path: impl AsRef<Path>) -> std::io::Result<HostFile> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)?;
+        Ok(HostFile { path: path.to_path_buf(), file, writable: false })
+    }
+
+    /// Open the file at `path` for reading and writing.
+    ///
+    /// The file must already exist.
+    pub fn new_writable(path: impl AsRef<Path>) -> std::io::Result<HostFile> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(HostFile { path: path.to_path_buf(), file, writable: true })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// The errno to give the kernel for a failed host I/O call.
+fn io_errno(err: std::io::Error) -> c_int {
+    err.raw_os_error().unwrap_or(EIO)
+}
+
+impl FileHandler for HostFile {
+    fn get_size(&self) -> u64 {
+        self.file.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+    }
+
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        let mut buf = vec![0; size as usize];
+        let mut filled = 0;
+        // The host file may have shrunk since its size was checked, so stop at its end
+        while filled < buf.len() {
+            match self.file.read_at(&mut buf[filled..], offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(io_errno(err)),
+            }
+        }
+        buf.truncate(filled);
+        Ok(buf)
+    }
+
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        self.file.write_all_at(data, offset).map_err(io_errno)?;
+        Ok(data.len() as u32)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        self.file.set_len(size).map_err(io_errno)
+    }
+
+    fn metadata(&self) -> Metadata {
+        let host = match self.file.metadata() {
+            Ok(host) => host,
+            Err(_) => return Metadata::default(),
+        };
+        let mut metadata = Metadata::new()
+            .perm((host.mode() & 0o7777) as u16)
+            .owner(host.uid(), host.gid());
+        metadata.atime = host.accessed().ok();
+        metadata.mtime = host.modified().ok();
+        metadata.ctime = std::time::UNIX_EPOCH.checked_add(std::time::Duration::new(host.ctime() as u64, host.ctime_nsec() as u32));
+        metadata.crtime = host.created().ok();
+        metadata
+    }
+}
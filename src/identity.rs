@@ -1,11 +1,17 @@
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 /// A unique identifier for an item (file or directory) in a file system.
 /// 
 /// This is used to create a unique inode number for each item in the file system.
 /// 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct ItemIdentity(u64);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) enum ItemIdentity {
+    /// Drawn at random, so it is different every time the program runs.
+    Random(u64),
+    /// Derived from a name, key or path, so it is the same every time the program runs.
+    Stable(u64),
+}
 
 impl ItemIdentity {
     /// Create a new identity.
@@ -15,6 +21,143 @@ impl ItemIdentity {
     pub fn new() -> Self {
         let mut rng = rand::thread_rng();
         let id = rng.gen::<u64>();
-        ItemIdentity(id)
+        ItemIdentity::Random(id)
     }
-}
\ No newline at end of file
+
+    /// Create an identity from a user-supplied key.
+    /// 
+    /// The same key always gives the same identity.
+    /// 
+    pub fn from_key(key: &str) -> Self {
+        ItemIdentity::Stable(fnv1a(b"key:".iter().chain(key.as_bytes())))
+    }
+
+    /// Create an identity from the absolute path of an item inside the file system.
+    /// 
+    pub fn from_path(path: &str) -> Self {
+        ItemIdentity::Stable(fnv1a(b"path:".iter().chain(path.as_bytes())))
+    }
+
+    /// Derive the identity of the item called `name` inside the item with this identity.
+    /// 
+    /// Unlike [`ItemIdentity::new`], this always gives the same result for the same inputs,
+    /// so items that are recreated on every request still keep their inode number.
+    /// The result is only stable across restarts if this identity is.
+    /// 
+    pub fn child(&self, name: &str) -> Self {
+        let hash = fnv1a(self.value().to_le_bytes().iter().chain(name.as_bytes()));
+        match self {
+            ItemIdentity::Random(_) => ItemIdentity::Random(hash),
+            ItemIdentity::Stable(_) => ItemIdentity::Stable(hash),
+        }
+    }
+
+    /// Whether this identity survives a restart of the program.
+    pub fn is_stable(&self) -> bool {
+        matches!(self, ItemIdentity::Stable(_))
+    }
+
+    /// The inode number this identity should get if it is free,
+    /// so that stable identities get the same one on every mount, whatever order items are seen in.
+    pub fn preferred_ino(&self) -> Option<u64> {
+        match self {
+            ItemIdentity::Stable(id) => Some(*id),
+            ItemIdentity::Random(_) => None,
+        }
+    }
+
+    fn value(&self) -> u64 {
+        match self {
+            ItemIdentity::Random(id) | ItemIdentity::Stable(id) => *id,
+        }
+    }
+}
+
+/// FNV-1a, which (unlike `DefaultHasher`) is guaranteed not to change between releases.
+fn fnv1a<'b>(bytes: impl Iterator<Item = &'b u8>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// How items without a user-supplied key get their identity, and so their inode number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdentityScheme {
+    /// Every handler gets a random identity when it is created.
+    /// 
+    /// Inode numbers are handed out in the order items are first seen,
+    /// so they differ between mounts.
+    /// The same handler added in two places shares an inode number.
+    #[default]
+    Random,
+    /// Items are identified by their path inside the file system.
+    /// 
+    /// Their inode numbers are derived from the path, so every path gets the same one on every mount.
+    Path,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::DirectoryListing;
+    use crate::testing::Harness;
+    use crate::RoutableFilesystem;
+
+    fn by_path() -> RoutableFilesystem<'static> {
+        let mut fs = RoutableFilesystem::new();
+        fs.set_identity_scheme(IdentityScheme::Path);
+        fs.set_root(DirectoryListing::new()
+            .add_static_file("x", "x")
+            .add_dir("dir", DirectoryListing::new().add_static_file("y", "y")));
+        fs
+    }
+
+    #[test]
+    fn child_identity_is_deterministic() {
+        let parent = ItemIdentity::new();
+        assert_eq!(parent.child("a"), parent.child("a"));
+        assert_ne!(parent.child("a"), parent.child("b"));
+        assert_ne!(parent.child("a"), ItemIdentity::new().child("a"));
+    }
+
+    #[test]
+    fn keys_and_paths_do_not_collide() {
+        assert_eq!(ItemIdentity::from_key("/a"), ItemIdentity::from_key("/a"));
+        assert_ne!(ItemIdentity::from_key("/a"), ItemIdentity::from_path("/a"));
+    }
+
+    #[test]
+    fn path_inodes_do_not_depend_on_lookup_order() {
+        let mut first = Harness::new(by_path());
+        let x = first.resolve("/x").unwrap();
+        let y = first.resolve("/dir/y").unwrap();
+        let mut second = Harness::new(by_path());
+        assert_eq!(second.resolve("/dir/y"), Ok(y));
+        assert_eq!(second.resolve("/x"), Ok(x));
+    }
+
+    #[test]
+    fn inode_tables_survive_a_restart() {
+        let table = std::env::temp_dir().join(format!("fusible-inodes-{}.json", std::process::id()));
+        let mut first = by_path();
+        first.persist_inodes(&table).unwrap();
+        let mut first = Harness::new(first);
+        let inos = [first.resolve("/x").unwrap(), first.resolve("/dir").unwrap(), first.resolve("/dir/y").unwrap()];
+        // Saved when the filesystem is dropped
+        drop(first);
+
+        let saved: serde_json::Value = serde_json::from_reader(std::fs::File::open(&table).unwrap()).unwrap();
+        // The root has a random identity, so it is left out
+        assert_eq!(saved["inodes"].as_array().unwrap().len(), 3);
+
+        let mut second = by_path();
+        second.load_inodes(&table).unwrap();
+        let mut second = Harness::new(second);
+        assert_eq!([second.resolve("/dir/y").unwrap(), second.resolve("/dir").unwrap(), second.resolve("/x").unwrap()], [inos[2], inos[1], inos[0]]);
+        std::fs::remove_file(table).unwrap();
+    }
+}
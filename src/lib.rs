@@ -1,7 +1,21 @@
+pub mod access;
+#[cfg(feature = "async")]
+pub mod async_handler;
+pub mod cache;
+pub mod command;
 pub mod fs;
 pub use fs::RoutableFilesystem;
 pub mod handler;
 pub mod identity;
+pub mod metadata;
+pub mod mount;
+pub mod router;
+pub use mount::{MountError, MountHandle, MountOptions};
+pub mod signal;
+pub mod statfs;
+pub mod testing;
+pub mod tree;
+pub use identity::IdentityScheme;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,180 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use time::Timespec;
+
+/// Attributes of a file or directory that `ls -l` and friends look at.
+///
+/// Every field is optional: anything left as `None` falls back to
+/// whatever the handler reports, and then to the filesystem's defaults.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub perm: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+    pub ctime: Option<SystemTime>,
+    pub crtime: Option<SystemTime>,
+    pub nlink: Option<u32>,
+    pub flags: Option<u32>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the permission bits, e.g. `0o644`.
+    pub fn perm(mut self, perm: u16) -> Self {
+        self.perm = Some(perm);
+        self
+    }
+
+    pub fn owner(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn atime(mut self, time: SystemTime) -> Self {
+        self.atime = Some(time);
+        self
+    }
+
+    pub fn mtime(mut self, time: SystemTime) -> Self {
+        self.mtime = Some(time);
+        self
+    }
+
+    pub fn ctime(mut self, time: SystemTime) -> Self {
+        self.ctime = Some(time);
+        self
+    }
+
+    pub fn crtime(mut self, time: SystemTime) -> Self {
+        self.crtime = Some(time);
+        self
+    }
+
+    /// Set every timestamp to the same time.
+    pub fn times(self, time: SystemTime) -> Self {
+        self.atime(time).mtime(time).ctime(time).crtime(time)
+    }
+
+    pub fn nlink(mut self, nlink: u32) -> Self {
+        self.nlink = Some(nlink);
+        self
+    }
+
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Fill in every field that is not set here from `fallback`.
+    pub fn or(self, fallback: Metadata) -> Metadata {
+        Metadata {
+            perm: self.perm.or(fallback.perm),
+            uid: self.uid.or(fallback.uid),
+            gid: self.gid.or(fallback.gid),
+            atime: self.atime.or(fallback.atime),
+            mtime: self.mtime.or(fallback.mtime),
+            ctime: self.ctime.or(fallback.ctime),
+            crtime: self.crtime.or(fallback.crtime),
+            nlink: self.nlink.or(fallback.nlink),
+            flags: self.flags.or(fallback.flags),
+        }
+    }
+}
+
+/// Convert a [`SystemTime`] into the timestamp type used by [`fuse`].
+///
+/// Times before the epoch are clamped to the epoch.
+pub(crate) fn to_timespec(time: SystemTime) -> Timespec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => Timespec::new(since_epoch.as_secs() as i64, since_epoch.subsec_nanos() as i32),
+        Err(_) => Timespec::new(0, 0),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libc::c_int;
+
+    use super::*;
+    use crate::handler::{DirectoryHandler, DirectoryListing, FileHandler, PathHandler};
+    use crate::testing::Harness;
+
+    /// A file that reports its own owner and times.
+    #[derive(Debug)]
+    struct Owned;
+
+    impl FileHandler for Owned {
+        fn get_size(&self) -> u64 {
+            0
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(Vec::new())
+        }
+
+        fn metadata(&self) -> Metadata {
+            Metadata::new().owner(1000, 100).perm(0o600).mtime(UNIX_EPOCH + Duration::from_secs(1000))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Shared;
+
+    impl<'a> DirectoryHandler<'a> for Shared {
+        fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+            Vec::new()
+        }
+
+        fn metadata(&self) -> Metadata {
+            Metadata::new().perm(0o1777).nlink(3)
+        }
+    }
+
+    #[test]
+    fn handlers_report_their_own_metadata() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_file("owned", Owned)
+            .add_dynamic_dir("shared", Shared)
+            .add_static_file("plain", "plain"));
+        let owned = harness.getattr("/owned").unwrap();
+        assert_eq!((owned.perm, owned.uid, owned.gid), (0o600, 1000, 100));
+        assert_eq!(owned.mtime, Timespec::new(1000, 0));
+        // Unset times fall back to the epoch
+        assert_eq!(owned.atime, Timespec::new(0, 0));
+
+        let shared = harness.getattr("/shared").unwrap();
+        assert_eq!((shared.perm, shared.nlink), (0o1777, 3));
+
+        let plain = harness.getattr("/plain").unwrap();
+        assert_eq!((plain.perm, plain.uid, plain.nlink), (0o755, 0, 1));
+    }
+
+    #[test]
+    fn overrides_are_merged_over_the_handler() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_file("owned", Owned)
+            .add_dynamic_dir("shared", Shared)
+            .with_entry_metadata("owned", Metadata::new().perm(0o640).mtime(UNIX_EPOCH + Duration::from_millis(2500)))
+            .with_entry_metadata("shared", Metadata::new().nlink(5))
+            .with_entry_metadata("missing", Metadata::new().perm(0o777)));
+        let owned = harness.getattr("/owned").unwrap();
+        assert_eq!(owned.perm, 0o640);
+        assert_eq!(owned.mtime, Timespec::new(2, 500_000_000));
+        // Fields the override leaves out still come from the handler
+        assert_eq!((owned.uid, owned.gid), (1000, 100));
+
+        let shared = harness.getattr("/shared").unwrap();
+        assert_eq!((shared.perm, shared.nlink), (0o1777, 5));
+        assert!(!harness.exists("/missing"));
+    }
+}
@@ -0,0 +1,326 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt::Display;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::JoinHandle;
+
+use fuse::{Filesystem, Session};
+use log::*;
+
+use crate::tree::TreeHandle;
+
+/// Whether the filesystem is mounted read-only or read-write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Access {
+    /// Read-write if any file in the tree is writable, read-only otherwise.
+    #[default]
+    Auto,
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Options passed to FUSE when mounting a [`RoutableFilesystem`](crate::RoutableFilesystem).
+///
+/// ```no_run
+/// # use fusible::{RoutableFilesystem, MountOptions};
+/// let fs = RoutableFilesystem::new();
+/// let options = MountOptions::new()
+///     .fsname("chunks")
+///     .allow_other()
+///     .auto_unmount();
+/// fs.mount_with("/mnt/chunks", &options).expect("failed to mount");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    fsname: Option<String>,
+    subtype: Option<String>,
+    allow_other: bool,
+    allow_root: bool,
+    default_permissions: bool,
+    auto_unmount: bool,
+    access: Access,
+    raw: Vec<String>,
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The name shown as the source of the mount, e.g. in `df` or `/proc/mounts`.
+    pub fn fsname(mut self, fsname: &str) -> Self {
+        self.fsname = Some(fsname.to_string());
+        self
+    }
+
+    /// The filesystem type shown as `fuse.<subtype>`.
+    pub fn subtype(mut self, subtype: &str) -> Self {
+        self.subtype = Some(subtype.to_string());
+        self
+    }
+
+    /// Let users other than the one who mounted the filesystem access it.
+    ///
+    /// Unless running as root, this needs `user_allow_other` in `/etc/fuse.conf`.
+    pub fn allow_other(mut self) -> Self {
+        self.allow_other = true;
+        self
+    }
+
+    /// Let root access the filesystem, in addition to the user who mounted it.
+    pub fn allow_root(mut self) -> Self {
+        self.allow_root = true;
+        self
+    }
+
+    /// Have the kernel check permissions against the reported file modes.
+    ///
+    /// This is the simplest form of access control: give items an owner and mode with
+    /// [`Metadata`](crate::metadata::Metadata) and the kernel does the rest, without asking the filesystem.
+    /// For rules that modes can't express, see [`RoutableFilesystem::set_access_policy`](crate::RoutableFilesystem::set_access_policy).
+    pub fn default_permissions(mut self) -> Self {
+        self.default_permissions = true;
+        self
+    }
+
+    /// Unmount automatically if the process exits without unmounting.
+    pub fn auto_unmount(mut self) -> Self {
+        self.auto_unmount = true;
+        self
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.access = Access::ReadOnly;
+        self
+    }
+
+    pub fn read_write(mut self) -> Self {
+        self.access = Access::ReadWrite;
+        self
+    }
+
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Pass an option to FUSE as-is, e.g. `"max_read=65536"`.
+    pub fn option(mut self, option: &str) -> Self {
+        self.raw.push(option.to_string());
+        self
+    }
+
+    /// Turn the options into the arguments expected by [`fuse::mount`].
+    ///
+    /// `writable` says whether the tree has writable files, for [`Access::Auto`].
+    pub(crate) fn to_args(&self, writable: bool) -> Result<Vec<OsString>, MountError> {
+        if self.allow_other && self.allow_root {
+            return Err(MountError::InvalidOptions("allow_other and allow_root cannot be used together".to_string()));
+        }
+
+        let mut options = Vec::new();
+        let read_only = match self.access {
+            Access::Auto => !writable,
+            Access::ReadOnly => true,
+            Access::ReadWrite => false,
+        };
+        options.push(if read_only { "ro" } else { "rw" }.to_string());
+        if let Some(fsname) = &self.fsname {
+            options.push(format!("fsname={fsname}"));
+        }
+        if let Some(subtype) = &self.subtype {
+            options.push(format!("subtype={subtype}"));
+        }
+        if self.allow_other {
+            options.push("allow_other".to_string());
+        }
+        if self.allow_root {
+            options.push("allow_root".to_string());
+        }
+        if self.default_permissions {
+            options.push("default_permissions".to_string());
+        }
+        if self.auto_unmount {
+            options.push("auto_unmount".to_string());
+        }
+        options.extend(self.raw.iter().cloned());
+
+        // Options are passed as one comma-separated list, so they can't contain commas themselves
+        if let Some(option) = options.iter().find(|option| option.is_empty() || option.contains(',')) {
+            return Err(MountError::InvalidOptions(format!("option {option:?} is empty or contains a comma")));
+        }
+
+        Ok(vec![OsString::from("-o"), OsString::from(options.join(","))])
+    }
+}
+
+/// A filesystem being served from a background thread,
+/// as returned by [`RoutableFilesystem::spawn_mount`](crate::RoutableFilesystem::spawn_mount).
+/// 
+/// Dropping the handle unmounts the filesystem and waits for the session to end.
+#[derive(Debug)]
+pub struct MountHandle {
+    mountpoint: PathBuf,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+    tree: Option<TreeHandle<'static>>,
+}
+
+impl MountHandle {
+    /// Run the session loop of an already-mounted filesystem on a new thread.
+    pub(crate) fn spawn<FS: Filesystem + Send + 'static>(mut session: Session<FS>) -> MountHandle {
+        let mountpoint = session.mountpoint().to_path_buf();
+        let thread = std::thread::spawn(move || session.run());
+        MountHandle { mountpoint, thread: Some(thread), tree: None }
+    }
+
+    pub(crate) fn with_tree(mut self, tree: TreeHandle<'static>) -> MountHandle {
+        self.tree = Some(tree);
+        self
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// A handle for changing the tree of the mounted filesystem,
+    /// if it is a [`RoutableFilesystem`](crate::RoutableFilesystem).
+    pub fn tree(&self) -> Option<TreeHandle<'static>> {
+        self.tree.clone()
+    }
+
+    /// Whether the session has ended, e.g. because someone ran `fusermount -u`.
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|thread| thread.is_finished())
+    }
+
+    /// Unmount the filesystem and wait for the session to end.
+    /// 
+    /// Returns the error the session ended with, if any.
+    pub fn unmount(mut self) -> Result<(), MountError> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<(), MountError> {
+        let finished = match &self.thread {
+            Some(thread) => thread.is_finished(),
+            None => return Ok(()),
+        };
+        // If the session already ended, the filesystem has been unmounted from outside.
+        // Otherwise the thread is only taken once unmounting worked, so a failed attempt can be retried.
+        if !finished {
+            unmount(&self.mountpoint)?;
+        }
+        let thread = self.thread.take().expect("the session thread is still there");
+        match thread.join() {
+            Ok(result) => result.map_err(MountError::Io),
+            Err(_) => Err(MountError::SessionPanicked),
+        }
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            error!("Failed to unmount {}: {err}", self.mountpoint.display());
+        }
+    }
+}
+
+/// Mount any [`Filesystem`] at `mountpoint` and serve it from a background thread.
+/// 
+/// This is a safe version of [`fuse::spawn_mount`] for filesystems that don't borrow anything.
+/// `options` are passed to FUSE as-is.
+pub fn spawn_mount<FS: Filesystem + Send + 'static>(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> Result<MountHandle, MountError> {
+    let session = Session::new(filesystem, mountpoint, options)?;
+    Ok(MountHandle::spawn(session))
+}
+
+/// Unmount the FUSE filesystem at `mountpoint`.
+/// 
+/// Unprivileged users can't call `umount(2)` on FUSE mounts,
+/// so this falls back to `fusermount -u`.
+pub fn unmount(mountpoint: &Path) -> std::io::Result<()> {
+    let path = CString::new(mountpoint.as_os_str().as_bytes())?;
+    if unsafe { libc::umount(path.as_ptr()) } == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) {
+        return Err(err);
+    }
+
+    let status = Command::new("fusermount").arg("-u").arg(mountpoint).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!("fusermount -u exited with {status}")))
+    }
+}
+
+/// Why mounting a filesystem failed.
+#[derive(Debug)]
+pub enum MountError {
+    /// The mount options contradict each other or can't be passed to FUSE.
+    InvalidOptions(String),
+    /// The mountpoint does not exist or is not a directory.
+    BadMountpoint(PathBuf),
+    /// FUSE failed to mount the filesystem, or the session ended with an error.
+    Io(std::io::Error),
+    /// The thread serving a background mount panicked.
+    SessionPanicked,
+}
+
+impl Display for MountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MountError::InvalidOptions(reason) => write!(f, "invalid mount options: {reason}"),
+            MountError::BadMountpoint(path) => write!(f, "mountpoint {} does not exist or is not a directory", path.display()),
+            MountError::Io(err) => write!(f, "FUSE error: {err}"),
+            MountError::SessionPanicked => write!(f, "the filesystem session panicked"),
+        }
+    }
+}
+
+impl std::error::Error for MountError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MountError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MountError {
+    fn from(err: std::io::Error) -> Self {
+        MountError::Io(err)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_follows_writability() {
+        assert_eq!(MountOptions::new().to_args(false).unwrap(), ["-o", "ro"]);
+        assert_eq!(MountOptions::new().to_args(true).unwrap(), ["-o", "rw"]);
+        assert_eq!(MountOptions::new().read_only().to_args(true).unwrap(), ["-o", "ro"]);
+    }
+
+    #[test]
+    fn options_are_joined() {
+        let options = MountOptions::new()
+            .fsname("chunks")
+            .allow_other()
+            .option("max_read=65536");
+        assert_eq!(options.to_args(false).unwrap(), ["-o", "ro,fsname=chunks,allow_other,max_read=65536"]);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(MountOptions::new().allow_other().allow_root().to_args(false).is_err());
+        assert!(MountOptions::new().fsname("a,b").to_args(false).is_err());
+    }
+}
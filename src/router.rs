@@ -0,0 +1,440 @@
+//! Directory trees described by path patterns, like the routes of a web server.
+//!
+//! A route such as `/logs/{date}/{service}.log` has a builder, which gets the captured values
+//! and makes the handler for a single path, and an enumerator, which lists every set of values
+//! that should show up in directory listings.
+//!
+//! ```
+//! # use fusible::{handler::{File, FileHandler}, router::{Params, Router}, testing::Harness};
+//! #[derive(Debug)]
+//! struct Chunk(u64);
+//!
+//! impl FileHandler for Chunk {
+//!     fn get_size(&self) -> u64 {
+//!         4
+//!     }
+//!
+//!     fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+//!         Ok(self.0.to_le_bytes()[offset as usize..][..size as usize].to_vec())
+//!     }
+//! }
+//!
+//! let router = Router::new().route(
+//!     "/chunks/{n}.part",
+//!     || (0..3).map(|n| Params::new().with("n", n)).collect(),
+//!     |params| {
+//!         let n: u64 = params.parse("n")?;
+//!         (n < 3).then(|| File::from_impl(Chunk(n)).into())
+//!     },
+//! );
+//! let mut harness = Harness::from_root(router);
+//! assert_eq!(harness.readdir("/chunks").unwrap().len(), 3);
+//! assert_eq!(harness.read("/chunks/2.part", 0, 1), Ok(vec![2]));
+//! assert!(!harness.exists("/chunks/3.part"));
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::handler::{DirectoryHandler, DynamicDirectory, PathHandler};
+
+/// The values captured by the `{name}` parts of a route.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a value, e.g. when listing the paths a route should have.
+    pub fn with(mut self, name: &str, value: impl ToString) -> Self {
+        self.insert(name, value.to_string());
+        self
+    }
+
+    fn insert(&mut self, name: &str, value: String) {
+        match self.values.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = value,
+            None => self.values.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The value called `name`, parsed as a `T`,
+    /// or `None` if there is no such value or it does not parse.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name)?.parse().ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Param(String),
+}
+
+/// One path component of a pattern, like `{service}.log`.
+#[derive(Debug, Clone)]
+struct Segment {
+    pieces: Vec<Piece>,
+}
+
+impl Segment {
+    fn parse(text: &str, pattern: &str) -> Segment {
+        let mut pieces = Vec::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            match rest.find('{') {
+                Some(0) => {
+                    let end = rest.find('}').unwrap_or_else(|| panic!("unclosed {{ in route {pattern:?}"));
+                    let name = &rest[1..end];
+                    if name.is_empty() || name.contains('{') {
+                        panic!("invalid parameter name {name:?} in route {pattern:?}");
+                    }
+                    // Two parameters in a row could be split anywhere
+                    if let Some(Piece::Param(_)) = pieces.last() {
+                        panic!("parameters must be separated by text in route {pattern:?}");
+                    }
+                    pieces.push(Piece::Param(name.to_string()));
+                    rest = &rest[end + 1..];
+                }
+                found => {
+                    let end = found.unwrap_or(rest.len());
+                    if rest[..end].contains('}') {
+                        panic!("unmatched }} in route {pattern:?}");
+                    }
+                    pieces.push(Piece::Literal(rest[..end].to_string()));
+                    rest = &rest[end..];
+                }
+            }
+        }
+        Segment { pieces }
+    }
+
+    fn params(&self) -> impl Iterator<Item = &str> {
+        self.pieces.iter().filter_map(|piece| match piece {
+            Piece::Param(name) => Some(name.as_str()),
+            Piece::Literal(_) => None,
+        })
+    }
+
+    /// Match a file name against this segment, adding the captured values to `params`.
+    fn capture(&self, name: &str, params: &mut Params) -> bool {
+        Self::capture_pieces(&self.pieces, name, params)
+    }
+
+    fn capture_pieces(pieces: &[Piece], name: &str, params: &mut Params) -> bool {
+        match pieces {
+            [] => name.is_empty(),
+            [Piece::Literal(literal), rest @ ..] => match name.strip_prefix(literal.as_str()) {
+                Some(name) => Self::capture_pieces(rest, name, params),
+                None => false,
+            },
+            [Piece::Param(param), rest @ ..] => {
+                // Values are never empty; try the shortest value that lets the rest match
+                for (end, _) in name.char_indices().skip(1).chain([(name.len(), ' ')]) {
+                    let mut attempt = params.clone();
+                    attempt.insert(param, name[..end].to_string());
+                    if Self::capture_pieces(rest, &name[end..], &mut attempt) {
+                        *params = attempt;
+                        return true;
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// The file name this segment has with the given values, if they are all there.
+    fn render(&self, params: &Params) -> Option<String> {
+        let mut name = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(literal) => name.push_str(literal),
+                Piece::Param(param) => name.push_str(params.get(param)?),
+            }
+        }
+        Some(name)
+    }
+}
+
+type Enumerator<'a> = Arc<dyn Fn() -> Vec<Params> + Send + Sync + 'a>;
+type Builder<'a> = Arc<dyn Fn(&Params) -> Option<PathHandler<'a>> + Send + Sync + 'a>;
+
+struct Route<'a> {
+    pattern: String,
+    segments: Vec<Segment>,
+    enumerate: Enumerator<'a>,
+    build: Builder<'a>,
+}
+
+impl<'a> Debug for Route<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Route").field(&self.pattern).finish()
+    }
+}
+
+impl<'a> Route<'a> {
+    /// Match the names of the directories leading to the current one,
+    /// returning the values captured so far.
+    fn capture_prefix(&self, names: &[String]) -> Option<Params> {
+        if self.segments.len() <= names.len() {
+            return None;
+        }
+        let mut params = Params::new();
+        for (segment, name) in self.segments.iter().zip(names) {
+            if !segment.capture(name, &mut params) {
+                return None;
+            }
+        }
+        Some(params)
+    }
+
+    /// Whether the enumerated `params` lead through the directories in `names`.
+    fn leads_through(&self, params: &Params, names: &[String]) -> bool {
+        self.segments.iter().zip(names).all(|(segment, name)| segment.render(params).as_ref() == Some(name))
+    }
+}
+
+/// A set of routes, which becomes a directory when added to a tree.
+///
+/// Patterns are relative to wherever the router is added,
+/// so `/{n}.part` added as `chunks` serves `chunks/0.part` and so on.
+///
+/// If several routes match a path, the one added first wins.
+/// Directories in the middle of a route only exist if the enumerator lists something inside them.
+#[derive(Debug, Clone, Default)]
+pub struct Router<'a> {
+    routes: Vec<Arc<Route<'a>>>,
+    writable: bool,
+}
+
+impl<'a> Router<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route.
+    ///
+    /// `enumerate` lists every set of values that should show up in directory listings.
+    /// `build` makes the handler for a path that matches the pattern, or returns `None` if there is no such item;
+    /// it is called for lookups too, so it can accept paths that `enumerate` does not list.
+    ///
+    /// # Panics
+    ///
+    /// If the pattern is malformed: unbalanced braces, an empty or repeated parameter name,
+    /// or two parameters in a row without text between them.
+    pub fn route(
+        mut self,
+        pattern: &str,
+        enumerate: impl Fn() -> Vec<Params> + Send + Sync + 'a,
+        build: impl Fn(&Params) -> Option<PathHandler<'a>> + Send + Sync + 'a,
+    ) -> Self {
+        let segments: Vec<Segment> = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| Segment::parse(segment, pattern))
+            .collect();
+        if segments.is_empty() {
+            panic!("route {pattern:?} has no path components");
+        }
+        let mut seen = HashSet::new();
+        for param in segments.iter().flat_map(|segment| segment.params()) {
+            if !seen.insert(param) {
+                panic!("parameter {param:?} appears twice in route {pattern:?}");
+            }
+        }
+
+        self.routes.push(Arc::new(Route {
+            pattern: pattern.to_string(),
+            segments,
+            enumerate: Arc::new(enumerate),
+            build: Arc::new(build),
+        }));
+        self
+    }
+
+    /// Mount the filesystem read-write if this router is part of it,
+    /// for routes whose files accept writes.
+    pub fn writable(mut self) -> Self {
+        self.writable = true;
+        self
+    }
+}
+
+impl<'a> From<Router<'a>> for PathHandler<'a> {
+    fn from(router: Router<'a>) -> Self {
+        let directory = RouterDirectory { routes: router.routes, writable: router.writable, names: Vec::new() };
+        PathHandler::DynamicDirectory(DynamicDirectory::from_impl(directory))
+    }
+}
+
+/// A directory at some depth of a router's tree.
+#[derive(Debug, Clone)]
+struct RouterDirectory<'a> {
+    routes: Vec<Arc<Route<'a>>>,
+    writable: bool,
+    /// The names of the directories from the router down to this one.
+    names: Vec<String>,
+}
+
+impl<'a> RouterDirectory<'a> {
+    fn child(&self, name: &str) -> PathHandler<'a> {
+        let mut names = self.names.clone();
+        names.push(name.to_string());
+        let directory = RouterDirectory { routes: self.routes.clone(), writable: self.writable, names };
+        PathHandler::DynamicDirectory(DynamicDirectory::from_impl(directory))
+    }
+}
+
+impl<'a> DirectoryHandler<'a> for RouterDirectory<'a> {
+    fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+        let depth = self.names.len();
+        let mut seen = HashSet::new();
+        let mut items = Vec::new();
+        for route in &self.routes {
+            if route.capture_prefix(&self.names).is_none() {
+                continue;
+            }
+            let segment = &route.segments[depth];
+            let is_leaf = route.segments.len() == depth + 1;
+            for params in (route.enumerate)() {
+                if !route.leads_through(&params, &self.names) {
+                    continue;
+                }
+                let name = match segment.render(&params) {
+                    Some(name) if !seen.contains(&name) => name,
+                    _ => continue,
+                };
+                let handler = if is_leaf {
+                    match (route.build)(&params) {
+                        Some(handler) => handler,
+                        None => continue,
+                    }
+                } else {
+                    self.child(&name)
+                };
+                seen.insert(name.clone());
+                items.push((name, handler));
+            }
+        }
+        items
+    }
+
+    fn lookup(&self, name: &str) -> Option<PathHandler<'a>> {
+        let depth = self.names.len();
+        for route in &self.routes {
+            let mut params = match route.capture_prefix(&self.names) {
+                Some(params) => params,
+                None => continue,
+            };
+            if !route.segments[depth].capture(name, &mut params) {
+                continue;
+            }
+
+            if route.segments.len() == depth + 1 {
+                if let Some(handler) = (route.build)(&params) {
+                    return Some(handler);
+                }
+            } else {
+                let mut names = self.names.clone();
+                names.push(name.to_string());
+                if (route.enumerate)().iter().any(|params| route.leads_through(params, &names)) {
+                    return Some(self.child(name));
+                }
+            }
+        }
+        None
+    }
+
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{DirectoryListing, Symlink};
+    use crate::testing::Harness;
+
+    fn capture(segment: &str, name: &str) -> Option<Params> {
+        let mut params = Params::new();
+        Segment::parse(segment, segment).capture(name, &mut params).then_some(params)
+    }
+
+    #[test]
+    fn segments_capture_parameters() {
+        assert_eq!(capture("{n}.part", "12.part"), Some(Params::new().with("n", 12)));
+        assert_eq!(capture("{n}.part", ".part"), None);
+        assert_eq!(capture("{n}.part", "12.log"), None);
+        assert_eq!(capture("{a}-{b}", "x-y-z"), Some(Params::new().with("a", "x").with("b", "y-z")));
+        assert_eq!(capture("static", "static"), Some(Params::new()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn adjacent_parameters_are_rejected() {
+        Router::new().route("/{a}{b}", Vec::new, |_| None);
+    }
+
+    fn logs() -> Harness<'static> {
+        let router = Router::new().route(
+            "/logs/{date}/{service}.log",
+            || vec![
+                Params::new().with("date", "2024-01-01").with("service", "web"),
+                Params::new().with("date", "2024-01-01").with("service", "db"),
+                Params::new().with("date", "2024-01-02").with("service", "web"),
+            ],
+            |params| {
+                let target = format!("{}/{}", params.get("date")?, params.get("service")?);
+                Some(Symlink::new(&target).into())
+            },
+        );
+        Harness::from_root(DirectoryListing::new().add("routed", router))
+    }
+
+    fn names(harness: &mut Harness, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = harness.readdir(path).unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn listings_come_from_the_enumerator() {
+        let mut harness = logs();
+        assert_eq!(names(&mut harness, "/routed"), ["logs"]);
+        assert_eq!(names(&mut harness, "/routed/logs"), ["2024-01-01", "2024-01-02"]);
+        assert_eq!(names(&mut harness, "/routed/logs/2024-01-01"), ["db.log", "web.log"]);
+    }
+
+    #[test]
+    fn lookups_use_the_builder() {
+        let mut harness = logs();
+        assert_eq!(harness.readlink("/routed/logs/2024-01-02/web.log"), Ok("2024-01-02/web".to_string()));
+        // Leaves don't need to be enumerated, but the directories leading to them do
+        assert_eq!(harness.readlink("/routed/logs/2024-01-02/cron.log"), Ok("2024-01-02/cron".to_string()));
+        assert!(!harness.exists("/routed/logs/2024-01-03"));
+        assert!(!harness.exists("/routed/logs/2024-01-01/web.txt"));
+    }
+
+    #[test]
+    fn routed_items_keep_their_inodes() {
+        let mut harness = logs();
+        let dir = harness.resolve("/routed/logs/2024-01-01").unwrap();
+        let log = harness.resolve("/routed/logs/2024-01-01/web.log").unwrap();
+        names(&mut harness, "/routed/logs/2024-01-01");
+        assert_eq!(harness.resolve("/routed/logs/2024-01-01"), Ok(dir));
+        assert_eq!(harness.resolve("/routed/logs/2024-01-01/web.log"), Ok(log));
+        assert_ne!(harness.resolve("/routed/logs/2024-01-02/web.log"), Ok(log));
+    }
+}
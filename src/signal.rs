@@ -0,0 +1,131 @@
+//! Unmounting cleanly when the process is asked to stop.
+//!
+//! Killing a process that serves a FUSE filesystem leaves a stale mountpoint behind
+//! ("Transport endpoint is not connected") until someone runs `fusermount -u`.
+//! [`mount_until_signal`] catches the usual stop signals and unmounts first.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use fuse::Filesystem;
+use libc::{c_int, SIGHUP, SIGINT, SIGTERM};
+use log::*;
+
+use crate::mount::{spawn_mount, MountError, MountHandle};
+
+/// How long to wait for a signal before checking whether the filesystem was unmounted from outside.
+const POLL_INTERVAL_NANOS: i64 = 200_000_000;
+
+/// Why [`mount_until_signal`] stopped serving the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// The process received this signal, and the filesystem was unmounted.
+    Signal(c_int),
+    /// Someone else unmounted the filesystem, e.g. with `fusermount -u`.
+    Unmounted,
+}
+
+impl Shutdown {
+    /// The conventional exit status for the process: `128 + signal` after a signal, `0` otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Shutdown::Signal(signal) => 128 + signal,
+            Shutdown::Unmounted => 0,
+        }
+    }
+}
+
+fn shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, SIGINT);
+        libc::sigaddset(&mut set, SIGTERM);
+        libc::sigaddset(&mut set, SIGHUP);
+        set
+    }
+}
+
+/// Block `SIGINT`, `SIGTERM` and `SIGHUP` in the calling thread,
+/// and in every thread it spawns from now on,
+/// so that they can be picked up by [`wait_for_shutdown`] instead of killing the process.
+pub fn block_shutdown_signals() {
+    set_signal_mask(libc::SIG_BLOCK, &shutdown_signals());
+}
+
+/// Change the signal mask of the calling thread, returning the previous one.
+fn set_signal_mask(how: c_int, set: &libc::sigset_t) -> libc::sigset_t {
+    unsafe {
+        let mut previous: libc::sigset_t = std::mem::zeroed();
+        libc::pthread_sigmask(how, set, &mut previous);
+        previous
+    }
+}
+
+/// Wait until a shutdown signal arrives or the filesystem is unmounted from outside,
+/// then unmount it and wait for its session to end.
+///
+/// [`block_shutdown_signals`] must have been called before the filesystem was mounted,
+/// otherwise the signals still kill the process.
+pub fn wait_for_shutdown(handle: MountHandle) -> Result<Shutdown, MountError> {
+    let set = shutdown_signals();
+    let timeout = libc::timespec { tv_sec: 0, tv_nsec: POLL_INTERVAL_NANOS };
+    loop {
+        if handle.is_finished() {
+            handle.unmount()?;
+            return Ok(Shutdown::Unmounted);
+        }
+        let signal = unsafe { libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout) };
+        // A negative result means the wait timed out or was interrupted
+        if signal > 0 {
+            info!("Got signal {signal}, unmounting {}", handle.mountpoint().display());
+            handle.unmount()?;
+            return Ok(Shutdown::Signal(signal));
+        }
+    }
+}
+
+/// Mount any [`Filesystem`] at `mountpoint` and serve it until the process gets
+/// `SIGINT`, `SIGTERM` or `SIGHUP`, then unmount it cleanly.
+///
+/// The filesystem is dropped before this returns, so state can be flushed in its [`Drop`] impl.
+/// `options` are passed to FUSE as-is.
+///
+/// The signals are blocked in the calling thread; threads spawned before this call
+/// should block them too (see [`block_shutdown_signals`]).
+/// If the filesystem can't be mounted, the calling thread's signal mask is left as it was.
+pub fn mount_until_signal<FS: Filesystem + Send + 'static>(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> Result<Shutdown, MountError> {
+    // Blocked before mounting, so that the session thread inherits the mask
+    let previous = set_signal_mask(libc::SIG_BLOCK, &shutdown_signals());
+    let handle = match spawn_mount(filesystem, mountpoint, options) {
+        Ok(handle) => handle,
+        Err(err) => {
+            set_signal_mask(libc::SIG_SETMASK, &previous);
+            return Err(err);
+        }
+    };
+    wait_for_shutdown(handle)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RoutableFilesystem;
+
+    fn is_blocked(signal: c_int) -> bool {
+        unsafe {
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask);
+            libc::sigismember(&mask, signal) == 1
+        }
+    }
+
+    #[test]
+    fn failed_mounts_leave_signals_alone() {
+        let mountpoint = std::env::temp_dir().join(format!("fusible-missing-{}", std::process::id()));
+        assert!(mount_until_signal(RoutableFilesystem::new(), &mountpoint, &[]).is_err());
+        assert!(!is_blocked(SIGINT));
+        assert!(!is_blocked(SIGTERM));
+    }
+}
@@ -0,0 +1,164 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::handler::{File, PathHandler};
+
+/// What `df` and `statvfs(3)` report for the filesystem.
+///
+/// Without a provider (see [`RoutableFilesystem::set_statfs`](crate::RoutableFilesystem::set_statfs)),
+/// the filesystem reports the total size of the files in its tree as used space.
+/// Read-only trees have nothing free, like `/proc`;
+/// writable ones report [`StatFs::WRITABLE_FREE_BYTES`] and [`StatFs::WRITABLE_FREE_FILES`] free,
+/// so that programs which check for space before writing don't give up.
+///
+/// Totalling the tree asks every file for its size, so each `df` renders every
+/// [`File::from_render`](crate::handler::File::from_render) file in it.
+/// Set a provider if that is too slow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// Total size, in units of `frsize`.
+    pub blocks: u64,
+    /// Free space, in units of `frsize`.
+    pub bfree: u64,
+    /// Free space available to unprivileged users, in units of `frsize`.
+    pub bavail: u64,
+    /// Total number of inodes.
+    pub files: u64,
+    /// Number of free inodes.
+    pub ffree: u64,
+    /// The preferred I/O size.
+    pub bsize: u32,
+    /// The longest allowed file name.
+    pub namelen: u32,
+    /// The unit that block counts are given in.
+    pub frsize: u32,
+}
+
+impl StatFs {
+    pub const BLOCK_SIZE: u32 = 4096;
+
+    /// The free space reported for writable trees without a provider.
+    pub const WRITABLE_FREE_BYTES: u64 = 1 << 30;
+
+    /// The number of free inodes reported for writable trees without a provider.
+    pub const WRITABLE_FREE_FILES: u64 = 1 << 20;
+
+    /// A full filesystem of `used` bytes in `files` items.
+    pub fn used(used: u64, files: u64) -> StatFs {
+        StatFs::with_capacity(used, 0, files, 0)
+    }
+
+    /// A filesystem of `capacity` bytes, of which `free` are free,
+    /// with room for `files` items, of which `free_files` are unused.
+    pub fn with_capacity(capacity: u64, free: u64, files: u64, free_files: u64) -> StatFs {
+        let blocks = |bytes: u64| bytes.div_ceil(StatFs::BLOCK_SIZE as u64);
+        StatFs {
+            blocks: blocks(capacity),
+            // Rounding down, so that what is reported as free can really be written
+            bfree: free / StatFs::BLOCK_SIZE as u64,
+            bavail: free / StatFs::BLOCK_SIZE as u64,
+            files,
+            ffree: free_files,
+            bsize: StatFs::BLOCK_SIZE,
+            namelen: 255,
+            frsize: StatFs::BLOCK_SIZE,
+        }
+    }
+
+    /// The numbers of the host filesystem that `path` is on,
+    /// for trees that pass writes through to it.
+    pub fn of_host(path: impl AsRef<Path>) -> std::io::Result<StatFs> {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(StatFs {
+            blocks: stat.f_blocks as u64,
+            bfree: stat.f_bfree as u64,
+            bavail: stat.f_bavail as u64,
+            files: stat.f_files as u64,
+            ffree: stat.f_ffree as u64,
+            bsize: stat.f_bsize as u32,
+            namelen: stat.f_namemax as u32,
+            frsize: stat.f_frsize as u32,
+        })
+    }
+
+    /// Collect the files at and below `item` into `files`, returning how many items there are in all.
+    ///
+    /// The contents of dynamic directories are not listed, since that could be expensive,
+    /// so only the directories themselves are counted.
+    /// Nothing is sized yet, so this is cheap enough to do while holding the tree.
+    pub(crate) fn collect_files<'a>(item: &PathHandler<'a>, files: &mut Vec<File<'a>>) -> u64 {
+        match item {
+            PathHandler::File(file) => {
+                files.push(file.clone());
+                1
+            }
+            PathHandler::Directory(dir) => 1 + dir.listdir().iter().map(|(_, child)| Self::collect_files(child, files)).sum::<u64>(),
+            PathHandler::DynamicDirectory(_) | PathHandler::Symlink(_) => 1,
+        }
+    }
+
+    /// Add up the sizes of `files`, out of `items` items in a tree that is `writable` or not.
+    pub(crate) fn of_tree(files: &[File], items: u64, writable: bool) -> StatFs {
+        let bytes = files.iter().map(File::get_size).sum();
+        if writable {
+            StatFs::with_capacity(bytes + Self::WRITABLE_FREE_BYTES, Self::WRITABLE_FREE_BYTES, items + Self::WRITABLE_FREE_FILES, Self::WRITABLE_FREE_FILES)
+        } else {
+            StatFs::used(bytes, items)
+        }
+    }
+
+    pub(crate) fn reply(&self, reply: fuse::ReplyStatfs) {
+        reply.statfs(self.blocks, self.bfree, self.bavail, self.files, self.ffree, self.bsize, self.namelen, self.frsize);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{DirectoryListing, File, HostFile};
+    use crate::RoutableFilesystem;
+
+    #[test]
+    fn tree_is_totalled_by_default() {
+        let mut fs = RoutableFilesystem::new();
+        fs.set_root(DirectoryListing::new()
+            .add_static_file("a", vec![0; 5000])
+            .add_dir("dir", DirectoryListing::new().add_static_file("b", "b"))
+            .add_symlink("link", "a"));
+        assert_eq!(fs.do_statfs(), StatFs::used(5001, 5));
+        assert_eq!(fs.do_statfs().blocks, 2);
+
+        fs.set_statfs(|| StatFs::with_capacity(1 << 20, 1 << 19, 10, 9));
+        assert_eq!(fs.do_statfs().bavail, 128);
+        assert_eq!(fs.do_statfs().ffree, 9);
+    }
+
+    #[test]
+    fn writable_trees_have_room() {
+        let mut fs = RoutableFilesystem::new();
+        fs.set_root(DirectoryListing::new()
+            .add_static_file("a", vec![0; 5000])
+            .add_file("w", HostFile::new_writable("/dev/null").unwrap()));
+        let stats = fs.do_statfs();
+        assert_eq!(stats.bfree, StatFs::WRITABLE_FREE_BYTES / StatFs::BLOCK_SIZE as u64);
+        assert_eq!(stats.bavail, stats.bfree);
+        assert_eq!(stats.ffree, StatFs::WRITABLE_FREE_FILES);
+        assert_eq!(stats.files, 3 + StatFs::WRITABLE_FREE_FILES);
+    }
+
+    #[test]
+    fn files_are_sized_without_holding_the_tree() {
+        let mut fs = RoutableFilesystem::new();
+        let tree = fs.tree();
+        let handle = tree.clone();
+        // Rendering takes the tree's lock, which would deadlock if `df` still held it
+        tree.insert("/rendered", File::from_render(move || vec![0; handle.contains("/rendered") as usize])).unwrap();
+        assert_eq!(fs.do_statfs(), StatFs::used(1, 2));
+    }
+}
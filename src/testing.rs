@@ -0,0 +1,594 @@
+//! Drive a [`RoutableFilesystem`] without mounting it.
+//!
+//! [`Harness`] sends the same operations the kernel would, by path instead of by inode,
+//! and goes through the same code as a mounted filesystem.
+//! This makes it possible to unit test handler trees where `/dev/fuse` is not available.
+//!
+//! ```
+//! # use fusible::{handler::{DirectoryListing, FileHandler}, testing::Harness};
+//! #[derive(Debug)]
+//! struct Hello;
+//!
+//! impl FileHandler for Hello {
+//!     fn get_size(&self) -> u64 {
+//!         5
+//!     }
+//!
+//!     fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+//!         Ok(b"hello"[offset as usize..][..size as usize].to_vec())
+//!     }
+//! }
+//!
+//! let root = DirectoryListing::new()
+//!     .add_dir("dir", DirectoryListing::new().add_file("file", Hello));
+//! let mut harness = Harness::from_root(root);
+//! assert_eq!(harness.read("/dir/file", 0, 4096), Ok(b"hello".to_vec()));
+//! assert_eq!(harness.read("/dir/missing", 0, 4096), Err(libc::ENOENT));
+//! ```
+
+use fuse::FileAttr;
+use libc::{c_int, EBUSY, EEXIST, EISDIR, ENOENT, O_CREAT, O_RDONLY, O_WRONLY};
+
+use crate::access::RequestContext;
+use crate::fs::DirEntry;
+use crate::handler::PathHandler;
+use crate::statfs::StatFs;
+use crate::RoutableFilesystem;
+
+/// The inode of the root directory.
+const ROOT_INO: u64 = fuse::FUSE_ROOT_ID;
+
+/// A file opened with [`Harness::open`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFile {
+    pub ino: u64,
+    /// The file handle the kernel would have been given.
+    pub fh: u64,
+}
+
+/// Sends filesystem operations to a [`RoutableFilesystem`] by path.
+///
+/// Every method returns the errno the kernel would have been given on failure.
+pub struct Harness<'a> {
+    fs: RoutableFilesystem<'a>,
+}
+
+impl<'a> Harness<'a> {
+    pub fn new(fs: RoutableFilesystem<'a>) -> Self {
+        Harness { fs }
+    }
+
+    /// Build a filesystem with the given root directory.
+    pub fn from_root(root: impl Into<PathHandler<'a>>) -> Self {
+        let mut fs = RoutableFilesystem::new();
+        fs.set_root(root);
+        Harness::new(fs)
+    }
+
+    /// The filesystem being driven, e.g. to check its inode bookkeeping.
+    pub fn filesystem(&mut self) -> &mut RoutableFilesystem<'a> {
+        &mut self.fs
+    }
+
+    /// Send the following operations on behalf of `context`, instead of root.
+    pub fn set_context(&mut self, context: RequestContext) {
+        self.fs.request = context;
+    }
+
+    /// Look up every component of `path` in turn, like the kernel does, and return the inode.
+    pub fn resolve(&mut self, path: &str) -> Result<u64, c_int> {
+        let mut ino = ROOT_INO;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = self.fs.do_lookup(ino, name)?.ino;
+        }
+        Ok(ino)
+    }
+
+    /// Split `path` into the inode of its parent directory and its name,
+    /// or `None` if it is the root.
+    fn resolve_parent<'p>(&mut self, path: &'p str) -> Result<Option<(u64, &'p str)>, c_int> {
+        match path.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, name)) if !name.is_empty() => Ok(Some((self.resolve(parent)?, name))),
+            _ => Ok(None),
+        }
+    }
+
+    /// The attributes the kernel would get when looking up `path`.
+    pub fn lookup(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        match self.resolve_parent(path)? {
+            Some((parent, name)) => self.fs.do_lookup(parent, name),
+            None => self.getattr(path),
+        }
+    }
+
+    pub fn getattr(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_getattr(ino)
+    }
+
+    /// List a directory, leaving out `.` and `..`.
+    pub fn readdir(&mut self, path: &str) -> Result<Vec<DirEntry>, c_int> {
+        let ino = self.resolve(path)?;
+        let entries = self.fs.do_readdir(ino)?;
+        Ok(entries.into_iter().filter(|entry| entry.name != "." && entry.name != "..").collect())
+    }
+
+    /// Open `path` with the given `open(2)` flags, keeping it open until [`release`](Harness::release).
+    pub fn open(&mut self, path: &str, flags: c_int) -> Result<OpenFile, c_int> {
+        let ino = self.resolve(path)?;
+        let (fh, _) = self.fs.do_open(ino, flags as u32)?;
+        Ok(OpenFile { ino, fh })
+    }
+
+    /// Read up to `size` bytes from `offset` through an open file.
+    pub fn read_open(&mut self, file: OpenFile, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.fs.do_read(file.ino, file.fh, offset, size)
+    }
+
+    /// Read from `offset` to the end through an open file, in chunks the size the kernel would use.
+    pub fn read_open_to_end(&mut self, file: OpenFile, offset: u64) -> Result<Vec<u8>, c_int> {
+        let mut content = Vec::new();
+        loop {
+            let chunk = self.fs.do_read(file.ino, file.fh, offset + content.len() as u64, 128 * 1024)?;
+            if chunk.is_empty() {
+                return Ok(content);
+            }
+            content.extend_from_slice(&chunk);
+        }
+    }
+
+    /// Write `data` at `offset` through an open file.
+    pub fn write_open(&mut self, file: OpenFile, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        self.fs.do_write(file.ino, file.fh, offset, data)
+    }
+
+    /// Close an open file, like the last `close(2)` of its file descriptor.
+    pub fn release(&mut self, file: OpenFile) -> Result<(), c_int> {
+        self.fs.do_flush(file.ino, file.fh)?;
+        self.fs.do_release(file.ino, file.fh)
+    }
+
+    /// Open `path` for reading, read up to `size` bytes from `offset` and close it again.
+    pub fn read(&mut self, path: &str, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        let file = self.open(path, O_RDONLY)?;
+        let result = self.read_open(file, offset, size);
+        self.release(file)?;
+        result
+    }
+
+    /// Read the whole file at `path`, in chunks the size the kernel would use.
+    pub fn read_to_end(&mut self, path: &str) -> Result<Vec<u8>, c_int> {
+        let file = self.open(path, O_RDONLY)?;
+        let result = self.read_open_to_end(file, 0);
+        self.release(file)?;
+        result
+    }
+
+    /// Open `path` for writing, write `data` at `offset` and close it again.
+    pub fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        let file = self.open(path, O_WRONLY)?;
+        let result = self.write_open(file, offset, data);
+        self.release(file)?;
+        result
+    }
+
+    /// Change the size of the file at `path`.
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_setattr(ino, Some(size))
+    }
+
+    pub fn readlink(&mut self, path: &str) -> Result<String, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_readlink(ino)
+    }
+
+    pub fn getxattr(&mut self, path: &str, name: &str) -> Result<Vec<u8>, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_getxattr(ino, name)
+    }
+
+    pub fn listxattr(&mut self, path: &str) -> Result<Vec<String>, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_listxattr(ino)
+    }
+
+    pub fn setxattr(&mut self, path: &str, name: &str, value: &[u8]) -> Result<(), c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_setxattr(ino, name, value)
+    }
+
+    pub fn removexattr(&mut self, path: &str, name: &str) -> Result<(), c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_removexattr(ino, name)
+    }
+
+    /// Create the file `path` and open it with the given `open(2)` flags, like `open(2)` with `O_CREAT`.
+    pub fn create(&mut self, path: &str, flags: c_int) -> Result<OpenFile, c_int> {
+        let (parent, name) = self.resolve_parent(path)?.ok_or(EEXIST)?;
+        let (attr, fh, _) = self.fs.do_create(parent, name, 0o644, (flags | O_CREAT) as u32)?;
+        Ok(OpenFile { ino: attr.ino, fh })
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let (parent, name) = self.resolve_parent(path)?.ok_or(EEXIST)?;
+        self.fs.do_mkdir(parent, name, 0o755)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> Result<(), c_int> {
+        let (parent, name) = self.resolve_parent(path)?.ok_or(EISDIR)?;
+        self.fs.do_unlink(parent, name)
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> Result<(), c_int> {
+        let (parent, name) = self.resolve_parent(path)?.ok_or(EBUSY)?;
+        self.fs.do_rmdir(parent, name)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), c_int> {
+        let (parent, name) = self.resolve_parent(from)?.ok_or(EBUSY)?;
+        let (new_parent, new_name) = self.resolve_parent(to)?.ok_or(EBUSY)?;
+        self.fs.do_rename(parent, name, new_parent, new_name)
+    }
+
+    /// Check `path` like `access(2)` does, with a mask like [`libc::R_OK`].
+    pub fn access(&mut self, path: &str, mask: c_int) -> Result<(), c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.do_access(ino, mask)
+    }
+
+    /// What `df` would show for the filesystem.
+    pub fn statfs(&mut self) -> StatFs {
+        self.fs.do_statfs()
+    }
+
+    /// Whether `path` exists.
+    pub fn exists(&mut self, path: &str) -> bool {
+        match self.resolve(path) {
+            Ok(_) => true,
+            Err(ENOENT) => false,
+            Err(_) => true,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use fuse::FileType;
+    use libc::{EACCES, EISDIR, ENODATA, ENOTDIR, ENOTSUP, EPERM, EROFS, EXDEV, O_RDWR};
+
+    use super::*;
+    use crate::access::{Operation, RequestContext};
+    use crate::handler::{DirectoryHandler, DirectoryListing, DynamicDirectory, FileHandler, File, HostFile, MutableDirectory, StaticFile};
+
+    #[derive(Debug)]
+    struct Memory(Mutex<Vec<u8>>);
+
+    impl FileHandler for Memory {
+        fn get_size(&self) -> u64 {
+            self.0.lock().unwrap().len() as u64
+        }
+
+        fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(self.0.lock().unwrap()[offset as usize..][..size as usize].to_vec())
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+
+        fn write(&self, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+            let mut content = self.0.lock().unwrap();
+            let end = offset as usize + data.len();
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[offset as usize..end].copy_from_slice(data);
+            Ok(data.len() as u32)
+        }
+    }
+
+    #[derive(Debug)]
+    struct ReadOnly;
+
+    impl FileHandler for ReadOnly {
+        fn get_size(&self) -> u64 {
+            10
+        }
+
+        fn read(&self, _offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(vec![b'x'; size as usize])
+        }
+    }
+
+    #[derive(Debug)]
+    struct Numbers;
+
+    impl<'a> DirectoryHandler<'a> for Numbers {
+        fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+            (0..3).map(|n| (n.to_string(), File::from_impl(ReadOnly).into())).collect()
+        }
+    }
+
+    /// A file that keeps extended attributes set on it.
+    #[derive(Debug, Default)]
+    struct Tagged(Mutex<BTreeMap<String, Vec<u8>>>);
+
+    impl FileHandler for Tagged {
+        fn get_size(&self) -> u64 {
+            0
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> Result<Vec<u8>, c_int> {
+            Ok(Vec::new())
+        }
+
+        fn list_xattrs(&self) -> Vec<String> {
+            self.0.lock().unwrap().keys().cloned().collect()
+        }
+
+        fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().get(name).cloned()
+        }
+
+        fn set_xattr(&self, name: &str, value: &[u8]) -> Result<(), c_int> {
+            self.0.lock().unwrap().insert(name.to_string(), value.to_vec());
+            Ok(())
+        }
+
+        fn remove_xattr(&self, name: &str) -> Result<(), c_int> {
+            self.0.lock().unwrap().remove(name).map(|_| ()).ok_or(ENODATA)
+        }
+    }
+
+    /// Directories that are rebuilt on every request, nested inside each other.
+    #[derive(Debug)]
+    struct Nested;
+
+    impl<'a> DirectoryHandler<'a> for Nested {
+        fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+            vec![
+                ("inner".to_string(), DynamicDirectory::from_impl(Numbers).into()),
+                ("listing".to_string(), DirectoryListing::new().add_file("leaf", ReadOnly).into()),
+            ]
+        }
+    }
+
+    /// A drop box that takes new files, but no directories.
+    #[derive(Debug, Default)]
+    struct Inbox(Mutex<BTreeMap<String, Arc<Memory>>>);
+
+    impl<'a> DirectoryHandler<'a> for Inbox {
+        fn list(&self) -> Vec<(String, PathHandler<'a>)> {
+            self.0.lock().unwrap().iter()
+                .map(|(name, file)| (name.clone(), File::from_shared(file.clone()).into()))
+                .collect()
+        }
+    }
+
+    impl<'a> MutableDirectory<'a> for Inbox {
+        fn create(&self, _context: &RequestContext, name: &str, _mode: u32) -> Result<File<'a>, c_int> {
+            let file = Arc::new(Memory(Mutex::new(Vec::new())));
+            self.0.lock().unwrap().insert(name.to_string(), file.clone());
+            Ok(File::from_shared(file))
+        }
+
+        fn unlink(&self, _context: &RequestContext, name: &str) -> Result<(), c_int> {
+            self.0.lock().unwrap().remove(name).map(|_| ()).ok_or(ENOENT)
+        }
+
+        fn rename(&self, _context: &RequestContext, name: &str, new_name: &str) -> Result<(), c_int> {
+            let mut files = self.0.lock().unwrap();
+            let file = files.remove(name).ok_or(ENOENT)?;
+            files.insert(new_name.to_string(), file);
+            Ok(())
+        }
+    }
+
+    fn harness() -> Harness<'static> {
+        Harness::from_root(DirectoryListing::new()
+            .add_mutable_dir("inbox", Inbox::default())
+            .add_dir("a", DirectoryListing::new()
+                .add_dir("b", DirectoryListing::new()
+                    .add_file("memory", Memory(Mutex::new(b"hello".to_vec())))))
+            .add_file("ro", ReadOnly)
+            .add_dynamic_dir("numbers", Numbers)
+            .add_dynamic_dir("nested", Nested)
+            .add_symlink("link", "a/b/memory"))
+    }
+
+    #[test]
+    fn nested_directories_resolve() {
+        let mut harness = harness();
+        assert_eq!(harness.getattr("/a/b").unwrap().kind, FileType::Directory);
+        let names: Vec<String> = harness.readdir("/a/b").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["memory"]);
+        assert_eq!(harness.readdir("/ro"), Err(ENOTDIR));
+        assert_eq!(harness.read("/a", 0, 10), Err(EISDIR));
+    }
+
+    #[test]
+    fn reads_are_clamped_at_the_end() {
+        let mut harness = harness();
+        assert_eq!(harness.read("/ro", 8, 4096), Ok(b"xx".to_vec()));
+        assert_eq!(harness.read("/ro", 20, 4096), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn writes_reach_the_handler() {
+        let mut harness = harness();
+        assert_eq!(harness.write("/a/b/memory", 5, b" world"), Ok(6));
+        assert_eq!(harness.read_to_end("/a/b/memory"), Ok(b"hello world".to_vec()));
+        assert_eq!(harness.getattr("/a/b/memory").unwrap().size, 11);
+        assert_eq!(harness.write("/ro", 0, b"nope"), Err(EACCES));
+    }
+
+    #[test]
+    fn dynamic_directories_keep_their_inodes() {
+        let mut harness = harness();
+        let first = harness.lookup("/numbers/1").unwrap().ino;
+        let listed = harness.readdir("/numbers").unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed.iter().find(|entry| entry.name == "1").unwrap().ino, first);
+        assert_eq!(harness.lookup("/numbers/1").unwrap().ino, first);
+        assert!(!harness.exists("/numbers/3"));
+    }
+
+    #[test]
+    fn nested_dynamic_directories_keep_their_inodes() {
+        let mut harness = harness();
+        let paths = ["/nested/inner", "/nested/inner/1", "/nested/listing", "/nested/listing/leaf"];
+        let first: Vec<u64> = paths.iter().map(|path| harness.resolve(path).unwrap()).collect();
+        let known = harness.filesystem().ino_to_handler.len();
+        let second: Vec<u64> = paths.iter().map(|path| harness.resolve(path).unwrap()).collect();
+        assert_eq!(first, second);
+        assert_eq!(harness.filesystem().ino_to_handler.len(), known);
+    }
+
+    #[test]
+    fn closures_can_back_files() {
+        let opens = std::sync::atomic::AtomicUsize::new(0);
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_fn_file("zeros", || 3, |_, size| Ok(vec![0; size as usize]))
+            .add_render_file("opens", || {
+                let n = opens.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                n.to_string().into_bytes()
+            }));
+        assert_eq!(harness.read("/zeros", 1, 10), Ok(vec![0, 0]));
+        // The first stat renders the content, then every open renders it again
+        assert_eq!(harness.getattr("/opens").unwrap().size, 1);
+        assert_eq!(harness.read_to_end("/opens"), Ok(b"1".to_vec()));
+        assert_eq!(harness.read_to_end("/opens"), Ok(b"2".to_vec()));
+    }
+
+    #[test]
+    fn host_files_pass_through() {
+        let path = std::env::temp_dir().join(format!("fusible-host-file-{}", std::process::id()));
+        std::fs::write(&path, b"on disk").unwrap();
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_static_file("static", "fixed")
+            .add_file("ro", HostFile::new(&path).unwrap())
+            .add_file("rw", HostFile::new_writable(&path).unwrap()));
+
+        assert_eq!(harness.read_to_end("/static"), Ok(b"fixed".to_vec()));
+        assert_eq!(harness.read_to_end("/ro"), Ok(b"on disk".to_vec()));
+        assert_eq!(harness.write("/ro", 0, b"x"), Err(EACCES));
+        assert_eq!(harness.write("/rw", 0, b"in"), Ok(2));
+        assert_eq!(harness.truncate("/rw", 4).unwrap().size, 4);
+        assert_eq!(std::fs::read(&path).unwrap(), b"in d");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn static_files_clamp_direct_reads() {
+        // Wrapping handlers call `read` without going through the clamp in `File`
+        let file = StaticFile::from("fixed");
+        assert_eq!(file.read(3, 10), Ok(b"ed".to_vec()));
+        assert_eq!(file.read(10, 4), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn opens_keep_their_own_snapshot() {
+        let renders = std::sync::atomic::AtomicUsize::new(0);
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add_render_file("render", || {
+                let n = renders.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                format!("render {n}").into_bytes()
+            }));
+        let first = harness.open("/render", O_RDONLY).unwrap();
+        let second = harness.open("/render", O_RDONLY).unwrap();
+        assert_ne!(first.fh, second.fh);
+        assert_eq!(harness.read_open_to_end(second, 0), Ok(b"render 2".to_vec()));
+        assert_eq!(harness.read_open_to_end(first, 0), Ok(b"render 1".to_vec()));
+        assert_eq!(harness.read_open(first, 7, 100), Ok(b"1".to_vec()));
+        harness.release(first).unwrap();
+        harness.release(second).unwrap();
+        assert!(harness.filesystem().sessions.is_empty());
+    }
+
+    #[test]
+    fn symlinks_report_their_target() {
+        let mut harness = harness();
+        assert_eq!(harness.readlink("/link"), Ok("a/b/memory".to_string()));
+        assert_eq!(harness.getattr("/link").unwrap().kind, FileType::Symlink);
+    }
+
+    #[test]
+    fn mutable_directories_take_new_items() {
+        let mut harness = harness();
+        let file = harness.create("/inbox/job", O_WRONLY).unwrap();
+        assert_eq!(harness.write_open(file, 0, b"work"), Ok(4));
+        harness.release(file).unwrap();
+        assert_eq!(harness.read_to_end("/inbox/job"), Ok(b"work".to_vec()));
+        assert_eq!(harness.resolve("/inbox/job"), Ok(file.ino));
+
+        harness.unlink("/inbox/job").unwrap();
+        assert!(!harness.exists("/inbox/job"));
+        assert!(!harness.filesystem().ino_to_handler.contains_key(&file.ino));
+
+        // Only directories that take new items count as writable, whatever is inside them
+        assert_eq!(harness.access("/inbox", libc::W_OK), Ok(()));
+        assert_eq!(harness.access("/a/b", libc::W_OK), Err(EACCES));
+        assert_eq!(harness.access("/a/b/memory", libc::W_OK), Ok(()));
+
+        assert_eq!(harness.mkdir("/inbox/dir").unwrap_err(), EPERM);
+        assert_eq!(harness.create("/a/new", O_WRONLY), Err(EROFS));
+        assert_eq!(harness.unlink("/numbers/0"), Err(EROFS));
+        assert_eq!(harness.rename("/inbox/job", "/a/job"), Err(EXDEV));
+    }
+
+    #[test]
+    fn renamed_items_keep_their_inode() {
+        let mut harness = harness();
+        let file = harness.create("/inbox/draft", O_WRONLY).unwrap();
+        harness.write_open(file, 0, b"text").unwrap();
+        harness.release(file).unwrap();
+
+        harness.rename("/inbox/draft", "/inbox/final").unwrap();
+        assert_eq!(harness.resolve("/inbox/final"), Ok(file.ino));
+        assert_eq!(harness.read_to_end("/inbox/final"), Ok(b"text".to_vec()));
+        assert!(!harness.exists("/inbox/draft"));
+
+        // A new item under the old name is a different file
+        let new = harness.create("/inbox/draft", O_WRONLY).unwrap();
+        harness.release(new).unwrap();
+        assert_ne!(new.ino, file.ino);
+    }
+
+    #[test]
+    fn failed_creates_leave_nothing_behind() {
+        let mut harness = harness();
+        harness.filesystem().set_access_policy(|_, path, operation| path != "/inbox/secret" || operation != Operation::Read);
+        assert_eq!(harness.create("/inbox/secret", O_RDWR), Err(EACCES));
+        assert!(!harness.exists("/inbox/secret"));
+        assert!(harness.readdir("/inbox").unwrap().is_empty());
+        // Creating it for writing only is allowed
+        let file = harness.create("/inbox/secret", O_WRONLY).unwrap();
+        harness.release(file).unwrap();
+    }
+
+    #[test]
+    fn xattrs_come_from_overrides_then_handlers() {
+        let mut harness = Harness::from_root(DirectoryListing::new()
+            .add("tagged", File::from_impl(Tagged::default()).with_xattr("user.fixed", "override"))
+            .add_file("ro", ReadOnly)
+            .add_dir("dir", DirectoryListing::new().with_xattr("user.dir", "yes")));
+        harness.setxattr("/tagged", "user.fixed", b"handler").unwrap();
+        harness.setxattr("/tagged", "user.extra", b"extra").unwrap();
+
+        assert_eq!(harness.getxattr("/tagged", "user.fixed"), Ok(b"override".to_vec()));
+        assert_eq!(harness.getxattr("/tagged", "user.extra"), Ok(b"extra".to_vec()));
+        assert_eq!(harness.listxattr("/tagged"), Ok(vec!["user.fixed".to_string(), "user.extra".to_string()]));
+        assert_eq!(harness.getxattr("/tagged", "user.missing"), Err(ENODATA));
+        harness.removexattr("/tagged", "user.extra").unwrap();
+        assert_eq!(harness.getxattr("/tagged", "user.extra"), Err(ENODATA));
+
+        assert_eq!(harness.getxattr("/dir", "user.dir"), Ok(b"yes".to_vec()));
+        assert_eq!(harness.setxattr("/dir", "user.dir", b"no"), Err(ENOTSUP));
+        assert_eq!(harness.setxattr("/ro", "user.x", b"x"), Err(ENOTSUP));
+    }
+}
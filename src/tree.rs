@@ -0,0 +1,253 @@
+//! Changing the tree of a filesystem while it is mounted.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::handler::{DirectoryListing, PathHandler};
+
+/// What happened at a path, for the filesystem to catch up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
+    Inserted,
+    Removed,
+    Replaced,
+}
+
+/// The tree shared between a filesystem and its [`TreeHandle`]s.
+#[derive(Debug)]
+pub(crate) struct SharedTree<'a> {
+    state: Mutex<TreeState<'a>>,
+    /// Set whenever `changes` is not empty, so requests can skip the lock otherwise.
+    dirty: AtomicBool,
+}
+
+#[derive(Debug)]
+pub(crate) struct TreeState<'a> {
+    pub(crate) root: PathHandler<'a>,
+    pub(crate) changes: Vec<(String, ChangeKind)>,
+}
+
+impl<'a> SharedTree<'a> {
+    pub(crate) fn new(root: PathHandler<'a>) -> Self {
+        SharedTree { state: Mutex::new(TreeState { root, changes: Vec::new() }), dirty: AtomicBool::new(false) }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, TreeState<'a>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Take the changes made since the last call, if there are any.
+    pub(crate) fn take_changes(&self) -> Option<MutexGuard<'_, TreeState<'a>>> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return None;
+        }
+        Some(self.lock())
+    }
+}
+
+/// Split a path into its components, ignoring empty ones.
+pub(crate) fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|name| !name.is_empty()).collect()
+}
+
+/// Turn a path into the form used by the filesystem's path maps, like `/a/b`.
+pub(crate) fn normalize(path: &str) -> String {
+    format!("/{}", components(path).join("/"))
+}
+
+impl<'a> TreeState<'a> {
+    /// The item at `path`, following [`DirectoryListing`]s only.
+    pub(crate) fn get(&self, path: &str) -> Option<&PathHandler<'a>> {
+        let mut item = &self.root;
+        for name in components(path) {
+            item = match item {
+                PathHandler::Directory(dir) => dir.entry(name)?,
+                _ => return None,
+            };
+        }
+        Some(item)
+    }
+
+    /// The directory that `path` goes in, and the name it has there.
+    fn parent_mut<'p>(&mut self, path: &'p str) -> Result<(&mut DirectoryListing<'a>, &'p str), TreeError> {
+        let names = components(path);
+        let (name, parents) = names.split_last().ok_or_else(|| TreeError::InvalidPath(path.to_string()))?;
+        let mut item = &mut self.root;
+        for (depth, parent) in parents.iter().enumerate() {
+            item = match item {
+                PathHandler::Directory(dir) => dir.entry_mut(parent),
+                _ => None,
+            }.ok_or_else(|| TreeError::NotFound(format!("/{}", names[..=depth].join("/"))))?;
+        }
+        match item {
+            PathHandler::Directory(dir) => Ok((dir, name)),
+            _ => Err(TreeError::NotADirectory(format!("/{}", parents.join("/")))),
+        }
+    }
+}
+
+/// A handle for adding, removing and replacing items while the filesystem is mounted.
+///
+/// Get one from [`RoutableFilesystem::tree`](crate::RoutableFilesystem::tree) before mounting,
+/// or from [`MountHandle::tree`](crate::MountHandle::tree) after [`spawn_mount`](crate::RoutableFilesystem::spawn_mount).
+/// Handles can be cloned and sent to other threads; changes show up with the next request.
+///
+/// Only items inside [`DirectoryListing`]s can be changed:
+/// the contents of dynamic directories and routers come from their handlers.
+///
+/// ```
+/// # use fusible::{RoutableFilesystem, handler::{DirectoryListing, File, StaticFile}};
+/// let mut fs = RoutableFilesystem::new();
+/// fs.set_root(DirectoryListing::new().add_dir("reports", DirectoryListing::new()));
+/// let tree = fs.tree();
+/// tree.insert("/reports/today.txt", File::from_impl(StaticFile::from("all good"))).unwrap();
+/// tree.remove("/reports/today.txt").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct TreeHandle<'a> {
+    tree: Arc<SharedTree<'a>>,
+}
+
+impl<'a> TreeHandle<'a> {
+    pub(crate) fn new(tree: Arc<SharedTree<'a>>) -> Self {
+        TreeHandle { tree }
+    }
+
+    fn record(&self, state: &mut TreeState<'a>, path: &str, kind: ChangeKind) {
+        state.changes.push((normalize(path), kind));
+        self.tree.dirty.store(true, Ordering::Release);
+    }
+
+    /// Add a new item at `path`, whose parent directory must already exist.
+    pub fn insert(&self, path: &str, item: impl Into<PathHandler<'a>>) -> Result<(), TreeError> {
+        let mut state = self.tree.lock();
+        let (parent, name) = state.parent_mut(path)?;
+        if parent.entry(name).is_some() {
+            return Err(TreeError::AlreadyExists(normalize(path)));
+        }
+        parent.insert(name, item.into());
+        self.record(&mut state, path, ChangeKind::Inserted);
+        Ok(())
+    }
+
+    /// Remove the item at `path`, and everything below it.
+    ///
+    /// Files that are open keep working until they are closed.
+    pub fn remove(&self, path: &str) -> Result<PathHandler<'a>, TreeError> {
+        let mut state = self.tree.lock();
+        let (parent, name) = state.parent_mut(path)?;
+        let item = parent.remove(name).ok_or_else(|| TreeError::NotFound(normalize(path)))?;
+        self.record(&mut state, path, ChangeKind::Removed);
+        Ok(item)
+    }
+
+    /// Put `item` in place of the one at `path`, keeping its inode number.
+    ///
+    /// Returns the item that was there before.
+    pub fn replace(&self, path: &str, item: impl Into<PathHandler<'a>>) -> Result<PathHandler<'a>, TreeError> {
+        let mut state = self.tree.lock();
+        let (parent, name) = state.parent_mut(path)?;
+        if parent.entry(name).is_none() {
+            return Err(TreeError::NotFound(normalize(path)));
+        }
+        let old = parent.insert(name, item.into()).expect("the item was just checked to exist");
+        self.record(&mut state, path, ChangeKind::Replaced);
+        Ok(old)
+    }
+
+    /// Whether there is an item at `path`, as far as the tree of [`DirectoryListing`]s goes.
+    pub fn contains(&self, path: &str) -> bool {
+        self.tree.lock().get(path).is_some()
+    }
+}
+
+/// Why a change to the tree could not be made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeError {
+    /// The path has no components, i.e. it is the root.
+    InvalidPath(String),
+    /// Nothing exists at this path.
+    NotFound(String),
+    /// This path is not a [`DirectoryListing`], so nothing can be changed inside it.
+    NotADirectory(String),
+    /// Something already exists at this path.
+    AlreadyExists(String),
+}
+
+impl Display for TreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeError::InvalidPath(path) => write!(f, "{path:?} does not name an item below the root"),
+            TreeError::NotFound(path) => write!(f, "{path} does not exist"),
+            TreeError::NotADirectory(path) => write!(f, "{path} is not a directory listing"),
+            TreeError::AlreadyExists(path) => write!(f, "{path} already exists"),
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{File, StaticFile};
+    use crate::testing::Harness;
+    use crate::RoutableFilesystem;
+
+    fn text(content: &'static str) -> File<'static> {
+        File::from_impl(StaticFile::from(content))
+    }
+
+    fn harness() -> (Harness<'static>, TreeHandle<'static>) {
+        let mut fs = RoutableFilesystem::new();
+        fs.set_root(DirectoryListing::new()
+            .add_dir("reports", DirectoryListing::new()
+                .add_dir("old", DirectoryListing::new().add_static_file("a.txt", "a")))
+            .add_static_file("motd", "hello"));
+        let tree = fs.tree();
+        (Harness::new(fs), tree)
+    }
+
+    #[test]
+    fn inserted_items_show_up() {
+        let (mut harness, tree) = harness();
+        // Look at the directory first, so its copy in the filesystem is stale
+        assert_eq!(harness.readdir("/reports").unwrap().len(), 1);
+        tree.insert("/reports/new.txt", text("new")).unwrap();
+        assert_eq!(harness.readdir("/reports").unwrap().len(), 2);
+        assert_eq!(harness.read_to_end("/reports/new.txt"), Ok(b"new".to_vec()));
+
+        assert_eq!(tree.insert("/reports/new.txt", text("again")), Err(TreeError::AlreadyExists("/reports/new.txt".to_string())));
+        assert_eq!(tree.insert("/missing/x", text("")), Err(TreeError::NotFound("/missing".to_string())));
+        assert_eq!(tree.insert("/motd/x", text("")), Err(TreeError::NotADirectory("/motd".to_string())));
+    }
+
+    #[test]
+    fn removed_items_are_forgotten() {
+        let (mut harness, tree) = harness();
+        let old = harness.resolve("/reports/old").unwrap();
+        let file = harness.resolve("/reports/old/a.txt").unwrap();
+        tree.remove("/reports/old").unwrap();
+
+        assert!(!harness.exists("/reports/old/a.txt"));
+        assert!(harness.readdir("/reports").unwrap().is_empty());
+        let fs = harness.filesystem();
+        for ino in [old, file] {
+            assert!(!fs.ino_to_handler.contains_key(&ino));
+            assert!(!fs.ino_parent.contains_key(&ino));
+            assert!(!fs.ino_to_path.contains_key(&ino));
+        }
+        assert!(!fs.path_to_ino.contains_key("/reports/old"));
+    }
+
+    #[test]
+    fn replaced_items_keep_their_inode() {
+        let (mut harness, tree) = harness();
+        let ino = harness.resolve("/motd").unwrap();
+        tree.replace("/motd", text("goodbye")).unwrap();
+        assert_eq!(harness.read_to_end("/motd"), Ok(b"goodbye".to_vec()));
+        assert_eq!(harness.resolve("/motd"), Ok(ino));
+    }
+}
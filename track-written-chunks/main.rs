@@ -18,6 +18,28 @@ struct HelloFS {
     size: u64
 }
 
+impl HelloFS {
+    /// Write out the chunk stats file
+    fn save_chunk_stats(&self) -> std::io::Result<()> {
+        let chunk_stats_file = File::create(&self.chunk_stats_file_name)?;
+        serde_json::to_writer_pretty(chunk_stats_file, &self.chunk_stats)?;
+        Ok(())
+    }
+}
+
+impl Drop for HelloFS {
+    fn drop(&mut self) {
+        // The filesystem is dropped once it has been unmounted,
+        // so make sure nothing written so far is lost
+        if let Err(err) = self.save_chunk_stats() {
+            println!("Error saving the chunk stats: {err:?}");
+        }
+        if let Err(err) = self.file.sync_all() {
+            println!("Error syncing the backing file: {err:?}");
+        }
+    }
+}
+
 impl Filesystem for HelloFS {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         if parent == 1 && name.to_str() == Some("file.bin") {
@@ -121,9 +143,9 @@ impl Filesystem for HelloFS {
                 for chunk in chunks.iter_mut() {
                     chunk.is_written = true;
                 }
-                // Write out the chunk stats file
-                let mut chunk_stats_file = File::create(&self.chunk_stats_file_name).unwrap();
-                chunk_stats_file.write_all(serde_json::to_string_pretty(&self.chunk_stats).unwrap().as_bytes()).unwrap();
+                if let Err(err) = self.save_chunk_stats() {
+                    println!("Error saving the chunk stats: {err:?}");
+                }
             }
 
             file.write_all(&data[..true_size]).unwrap();
@@ -179,7 +201,7 @@ struct ChunkInfo {
 
 /// Given a vector of ChunkInfo structs and a byte offset and size,
 /// return mutable references to the chunks that overlap with the given range.
-fn get_chunks_mut(chunks: &mut Vec<ChunkInfo>, offset: u64, size: u64) -> Vec<&mut ChunkInfo> {
+fn get_chunks_mut(chunks: &mut [ChunkInfo], offset: u64, size: u64) -> Vec<&mut ChunkInfo> {
     let mut result = Vec::new();
     let end_byte = offset + size;
     // The chunks are known to be in order and of the same size.
@@ -275,7 +297,8 @@ fn main() {
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-    fuse::mount(HelloFS{
+    let shutdown = fusible::signal::mount_until_signal(HelloFS{
         file, chunk_stats_file_name, chunk_stats, size
-    }, &mountpoint, &options).unwrap();
+    }, Path::new(&mountpoint), &options).unwrap();
+    std::process::exit(shutdown.exit_code());
 }